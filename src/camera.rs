@@ -0,0 +1,186 @@
+//! The view onto the map, shared with scripts through the `camera` global.
+//!
+//! `x` and `y` are the point shown in the middle of the screen. The camera can follow an object, only moving
+//! once the object leaves a deadzone around the middle, and is kept inside the map unless `clamp` is turned off.
+
+use std::collections::BTreeMap;
+use rlua::{self, Lua, UserData, UserDataMethods, MetaMethod, Value, AnyUserData};
+use ncollide::world::CollisionObjectHandle;
+use super::{MapInfo, Object};
+
+///Size of the view in world pixels when the map doesn't set `view_width` and `view_height`
+const DEFAULT_VIEW: f64 = 2048.0;
+
+pub struct Camera {
+    pub x: f64,
+    pub y: f64,
+    ///Position at the start of the current tick, for interpolating between ticks when drawing
+    pub last_x: f64,
+    pub last_y: f64,
+    pub zoom: f64,
+    ///Radians, clockwise
+    pub rotation: f64,
+    ///Size of the view in world pixels at a zoom of 1
+    pub width: f64,
+    pub height: f64,
+    ///Handle and generation of the followed object
+    target: Option<(CollisionObjectHandle, u64)>,
+    ///How far the target can get from the middle of the view before the camera moves
+    deadzone_width: f64,
+    deadzone_height: f64,
+    ///Fraction of the distance to the target still left to cover after a second, so 0 snaps straight to it
+    smoothing: f64,
+    clamp: bool,
+    ///Size of the map in pixels
+    map_width: f64,
+    map_height: f64,
+}
+
+impl Camera {
+    pub fn new(map: &MapInfo) -> Camera {
+        let property = |name: &str| map.properties.get(name).map_or(None, |prop| match prop {
+            ::tiled::PropertyValue::IntValue(v) => Some(*v as f64),
+            ::tiled::PropertyValue::FloatValue(v) => Some(*v as f64),
+            _ => None
+        });
+        let width = property("view_width").unwrap_or(DEFAULT_VIEW);
+        let height = property("view_height").unwrap_or(DEFAULT_VIEW);
+        let mut camera = Camera {
+            x: width / 2.0,
+            y: height / 2.0,
+            last_x: width / 2.0,
+            last_y: height / 2.0,
+            zoom: 1.0,
+            rotation: 0.0,
+            width,
+            height,
+            target: None,
+            deadzone_width: 0.0,
+            deadzone_height: 0.0,
+            smoothing: 0.0,
+            clamp: true,
+            map_width: (map.columns * map.tile_width) as f64,
+            map_height: (map.rows * map.tile_height) as f64,
+        };
+        camera.clamp_to_map();
+        camera.last_x = camera.x;
+        camera.last_y = camera.y;
+        camera
+    }
+
+    ///Moves towards the followed object, if there is one, then back inside the map
+    pub fn update(&mut self, objects: &BTreeMap<CollisionObjectHandle, (usize, Object)>, delta: f64) {
+        self.last_x = self.x;
+        self.last_y = self.y;
+        let target = self.target.and_then(|(handle, generation)| {
+            objects.get(&handle).filter(|&&(_, ref object)| object.generation == generation)
+        });
+        //Stops following once the object is destroyed, even if a new one gets its handle
+        if target.is_none() {
+            self.target = None;
+        }
+        if let Some(&(_, ref object)) = target {
+            let (left, top, right, bottom) = object.bounds();
+            let goal_x = follow(self.x, (left + right) / 2.0, self.deadzone_width / 2.0);
+            let goal_y = follow(self.y, (top + bottom) / 2.0, self.deadzone_height / 2.0);
+            let amount = 1.0 - self.smoothing.max(0.0).min(1.0).powf(delta);
+            self.x += (goal_x - self.x) * amount;
+            self.y += (goal_y - self.y) * amount;
+        }
+        self.clamp_to_map();
+    }
+
+    ///Where to draw from, `alpha` of the way between the last tick and this one
+    pub fn position(&self, alpha: f64) -> (f64, f64) {
+        (self.last_x + (self.x - self.last_x) * alpha, self.last_y + (self.y - self.last_y) * alpha)
+    }
+
+    fn clamp_to_map(&mut self) {
+        if !self.clamp {
+            return
+        }
+        self.x = clamp_axis(self.x, self.width / self.zoom / 2.0, self.map_width);
+        self.y = clamp_axis(self.y, self.height / self.zoom / 2.0, self.map_height);
+    }
+}
+
+///Where the middle of the view needs to be for `target` to be no more than `deadzone` away from it
+fn follow(middle: f64, target: f64, deadzone: f64) -> f64 {
+    if target > middle + deadzone {
+        target - deadzone
+    } else if target < middle - deadzone {
+        target + deadzone
+    } else {
+        middle
+    }
+}
+
+///Keeps a view `half` wide on each side of `middle` inside 0 to `size`, centring it if the map is smaller than the view
+fn clamp_axis(middle: f64, half: f64, size: f64) -> f64 {
+    if half * 2.0 >= size {
+        size / 2.0
+    } else {
+        middle.max(half).min(size - half)
+    }
+}
+
+impl UserData for Camera {
+    fn add_methods(methods: &mut UserDataMethods<Self>) {
+        methods.add_method_mut("follow", |_, camera, object: Option<AnyUserData>| {
+            camera.target = match object {
+                Some(object) => {
+                    let object = object.borrow::<Object>()?;
+                    Some((object.handle, object.generation))
+                }
+                None => None
+            };
+            Ok(())
+        });
+        //Jumps straight to a point instead of sliding there between ticks
+        methods.add_method_mut("move_to", |_, camera, (x, y): (f64, f64)| {
+            camera.x = x;
+            camera.y = y;
+            camera.clamp_to_map();
+            camera.last_x = camera.x;
+            camera.last_y = camera.y;
+            Ok(())
+        });
+        methods.add_method_mut("set_deadzone", |_, camera, (width, height): (f64, f64)| {
+            camera.deadzone_width = width;
+            camera.deadzone_height = height;
+            Ok(())
+        });
+        methods.add_meta_method(MetaMethod::Index, |_, camera, index: String| {
+            Ok(match index.as_str() {
+                "x" => Value::Number(camera.x),
+                "y" => Value::Number(camera.y),
+                "zoom" => Value::Number(camera.zoom),
+                "rotation" => Value::Number(camera.rotation),
+                "width" => Value::Number(camera.width),
+                "height" => Value::Number(camera.height),
+                "smoothing" => Value::Number(camera.smoothing),
+                "clamp" => Value::Boolean(camera.clamp),
+                "target" => camera.target.map_or(Value::Nil, |(handle, _)| Value::Integer(handle.0 as i64)),
+                _ => Value::Nil
+            })
+        });
+        methods.add_meta_method_mut(MetaMethod::NewIndex, |_: &Lua, camera: &mut Camera, (index, value): (String, Value)| {
+            let number = match value {
+                Value::Number(number) => Some(number),
+                Value::Integer(number) => Some(number as f64),
+                _ => None
+            };
+            match (index.as_str(), number, value) {
+                ("x", Some(x), _) => camera.x = x,
+                ("y", Some(y), _) => camera.y = y,
+                ("zoom", Some(zoom), _) if zoom > 0.0 => camera.zoom = zoom,
+                ("rotation", Some(rotation), _) => camera.rotation = rotation,
+                ("smoothing", Some(smoothing), _) => camera.smoothing = smoothing,
+                ("clamp", _, Value::Boolean(clamp)) => camera.clamp = clamp,
+                (index, _, _) => return Err(rlua::Error::RuntimeError(format!("Can't set camera.{} to that", index)))
+            }
+            camera.clamp_to_map();
+            Ok(())
+        });
+    }
+}
@@ -0,0 +1,130 @@
+//! Lines for the debug overlay: every shape in the collision world, where objects touch something solid,
+//! and a label for each object with its handle, position and rotation.
+
+use nalgebra::{Vector2, Point2, Isometry2};
+use ncollide::shape::{ShapeHandle2, Cuboid2, Plane2, Ball2, Compound2, ConvexHull2};
+use ncollide::query;
+use super::{Map, Collider, DebugVertex};
+
+const CONTACT_COLOR: [f32; 4] = [1.0, 1.0, 1.0, 1.0];
+const LABEL_COLOR: [f32; 4] = [1.0, 1.0, 1.0, 1.0];
+///Size of a label character, in world pixels
+const CHARACTER_WIDTH: f64 = 4.0;
+const CHARACTER_HEIGHT: f64 = 8.0;
+///How far from its shape something counts as touching it, matching the skin objects slide with
+const CONTACT_DISTANCE: f64 = 0.02;
+
+///Pairs of vertices, each pair one line
+pub fn lines(map: &Map) -> Vec<DebugVertex> {
+    let mut lines = Vec::new();
+    for collision_object in map.world.collision_objects() {
+        let color = match *collision_object.data() {
+            Collider::Map => [0.0, 1.0, 0.0, 1.0],
+            Collider::Object => [0.0, 0.8, 1.0, 1.0],
+            Collider::Hitbox(_) => [1.0, 0.8, 0.0, 1.0],
+            Collider::Hurtbox(_) => [1.0, 0.0, 0.0, 1.0],
+            Collider::Trigger => [1.0, 0.0, 1.0, 1.0],
+        };
+        outline(&mut lines, collision_object.position(), collision_object.shape(), color);
+    }
+
+    for &handle in map.objects.keys() {
+        let object = map.world.collision_object(handle).unwrap();
+        let contacts = map.world.collision_objects()
+            .filter(|other| other.handle() != handle && other.data().is_solid())
+            .filter_map(|other| query::contact(object.position(), object.shape().as_ref(), other.position(), other.shape().as_ref(), CONTACT_DISTANCE));
+        for contact in contacts {
+            let point = contact.world1;
+            let normal = -contact.normal.unwrap();
+            line(&mut lines, point + Vector2::new(-2.0, -2.0), point + Vector2::new(2.0, 2.0), CONTACT_COLOR);
+            line(&mut lines, point + Vector2::new(-2.0, 2.0), point + Vector2::new(2.0, -2.0), CONTACT_COLOR);
+            line(&mut lines, point, point + normal * 8.0, CONTACT_COLOR);
+        }
+    }
+
+    for (handle, &(_, ref object)) in &map.objects {
+        let (left, top, _, _) = object.bounds();
+        let label = format!("{} {} {} {}", handle.0, object.x.round(), object.y.round(), object.rotation);
+        text(&mut lines, &label, left, top - CHARACTER_HEIGHT - 2.0);
+    }
+    lines
+}
+
+fn line(lines: &mut Vec<DebugVertex>, start: Point2<f64>, end: Point2<f64>, color: [f32; 4]) {
+    lines.push(DebugVertex { pos: [start.x as f32, start.y as f32], color });
+    lines.push(DebugVertex { pos: [end.x as f32, end.y as f32], color });
+}
+
+///Lines joining every point to the next, and the last back to the first
+fn polygon(lines: &mut Vec<DebugVertex>, position: &Isometry2<f64>, points: &[Point2<f64>], color: [f32; 4]) {
+    for i in 0..points.len() {
+        line(lines, position * points[i], position * points[(i + 1) % points.len()], color);
+    }
+}
+
+fn outline(lines: &mut Vec<DebugVertex>, position: &Isometry2<f64>, shape: &ShapeHandle2<f64>, color: [f32; 4]) {
+    if let Some(cuboid) = shape.as_shape::<Cuboid2<f64>>() {
+        let half = cuboid.half_extents();
+        let corners = [
+            Point2::new(-half.x, -half.y),
+            Point2::new(half.x, -half.y),
+            Point2::new(half.x, half.y),
+            Point2::new(-half.x, half.y),
+        ];
+        polygon(lines, position, &corners, color);
+    } else if let Some(ball) = shape.as_shape::<Ball2<f64>>() {
+        let points: Vec<_> = (0..16).map(|i| {
+            let angle = i as f64 * ::std::f64::consts::PI / 8.0;
+            Point2::new(angle.cos() * ball.radius(), angle.sin() * ball.radius())
+        }).collect();
+        polygon(lines, position, &points, color);
+    } else if let Some(hull) = shape.as_shape::<ConvexHull2<f64>>() {
+        polygon(lines, position, hull.points(), color);
+    } else if let Some(plane) = shape.as_shape::<Plane2<f64>>() {
+        //Planes go on forever, so draw far enough past any map
+        let along = Vector2::new(-plane.normal().y, plane.normal().x) * 100000.0;
+        let origin = position * Point2::origin();
+        line(lines, origin - along, origin + along, color);
+    } else if let Some(compound) = shape.as_shape::<Compound2<f64>>() {
+        for &(ref part_position, ref part) in compound.shapes() {
+            outline(lines, &(position * part_position), part, color);
+        }
+    }
+}
+
+///Digits and minus signs drawn as seven segments, with anything else left as a gap
+fn text(lines: &mut Vec<DebugVertex>, text: &str, x: f64, y: f64) {
+    let (w, h) = (CHARACTER_WIDTH, CHARACTER_HEIGHT);
+    //Top, top right, bottom right, bottom, bottom left, top left, middle
+    let segments = [
+        ((0.0, 0.0), (w, 0.0)),
+        ((w, 0.0), (w, h / 2.0)),
+        ((w, h / 2.0), (w, h)),
+        ((0.0, h), (w, h)),
+        ((0.0, h / 2.0), (0.0, h)),
+        ((0.0, 0.0), (0.0, h / 2.0)),
+        ((0.0, h / 2.0), (w, h / 2.0)),
+    ];
+    for (i, character) in text.chars().enumerate() {
+        let lit: u8 = match character {
+            '0' => 0b0111111,
+            '1' => 0b0000110,
+            '2' => 0b1011011,
+            '3' => 0b1001111,
+            '4' => 0b1100110,
+            '5' => 0b1101101,
+            '6' => 0b1111101,
+            '7' => 0b0000111,
+            '8' => 0b1111111,
+            '9' => 0b1101111,
+            '-' => 0b1000000,
+            _ => 0
+        };
+        let left = x + i as f64 * (w + 2.0);
+        for (segment, &((x1, y1), (x2, y2))) in segments.iter().enumerate() {
+            if lit & (1 << segment) != 0 {
+                line(lines, Point2::new(left + x1, y + y1), Point2::new(left + x2, y + y2), LABEL_COLOR);
+            }
+        }
+    }
+}
@@ -0,0 +1,147 @@
+//! Runs a map without a window or GL context, for testing level logic on machines with no display.
+//!
+//! `lgj2018 --headless [map] [ticks] [inputs] [--record path] [--replay path]`
+//!
+//! The inputs file has one line per change in controls: the tick it happens on followed by the buttons
+//! held from then on, e.g. `120 right a`. Blank lines and lines starting with `#` are ignored.
+//! A replay takes the place of the inputs file and, unless a tick count is given, runs to its end.
+
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use rlua::Lua;
+use super::{Controls, Map, Tiles, set_globals};
+use replay::{Recorder, Replay};
+
+pub fn run(args: &[String], record: Option<String>, mut replay: Option<Replay>) {
+    let filename = args.get(0).map_or("assets/tiled/Finite.tmx", |arg| arg.as_str());
+    let default_ticks = replay.as_ref().map_or(600, |replay| replay.len() as u64);
+    let ticks: u64 = args.get(1).map_or(default_ticks, |arg| arg.parse().expect("Tick count should be a number"));
+    let inputs = args.get(2).map_or(Vec::new(), |path| load_inputs(path));
+
+    let lua = Lua::new();
+    let mut tiles = Tiles::new(&lua);
+    let mut map = Map::load(&mut tiles, filename).unwrap_or_else(|error| panic!("{}", error));
+    let tick = replay.as_ref().map_or(1.0 / map.tick_rate, |replay| replay.delta);
+    set_globals(&lua, tick);
+    let mut recorder = record.map(|path| Recorder::create(&path, tick));
+    let diverged = simulate(&lua, &tiles, &mut map, tick, ticks, inputs, recorder.as_mut(), replay.as_mut());
+    //Flushes the recording before a possible exit
+    drop(recorder);
+    report(&map, ticks);
+    if diverged {
+        std::process::exit(1);
+    }
+}
+
+///Runs `ticks` ticks with the controls from `inputs`, or from `replay` while it lasts, returning whether the replay diverged
+pub fn simulate(lua: &Lua, tiles: &Tiles, map: &mut Map, tick: f64, ticks: u64, inputs: Vec<(u64, Controls)>, mut recorder: Option<&mut Recorder>, mut replay: Option<&mut Replay>) -> bool {
+    let globals = lua.globals();
+    let mut inputs = inputs.into_iter().peekable();
+    let mut controls = Controls::default();
+    let mut diverged = false;
+    let mut counter = 0.0;
+    for i in 0..ticks {
+        while inputs.peek().map_or(false, |&(start, _)| start <= i) {
+            controls = inputs.next().unwrap().1;
+        }
+        let tick_controls = match replay.as_mut().and_then(|replay| replay.next()) {
+            Some(replayed) => replayed,
+            None => controls
+        };
+        globals.set("controls", tick_controls).unwrap();
+        counter += tick;
+        map.tick(lua, tiles, counter);
+        let hash = map.state_hash();
+        if let Some(ref mut replay) = replay {
+            if !replay.check(hash) {
+                diverged = true;
+            }
+        }
+        if let Some(ref mut recorder) = recorder {
+            recorder.record(tick_controls, hash);
+        }
+    }
+    diverged
+}
+
+pub fn load_inputs(path: &str) -> Vec<(u64, Controls)> {
+    let file = File::open(path).expect("Couldn't open inputs");
+    let mut inputs = Vec::new();
+    for line in BufReader::new(file).lines() {
+        let line = line.expect("Failed to read inputs");
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue
+        }
+        let mut words = line.split_whitespace();
+        let start = words.next().unwrap().parse().expect("Input line should start with a tick");
+        let mut controls = Controls::default();
+        for word in words {
+            match word {
+                "up" => controls.up = true,
+                "down" => controls.down = true,
+                "left" => controls.left = true,
+                "right" => controls.right = true,
+                "a" => controls.a = true,
+                "b" => controls.b = true,
+                _ => println!("Unknown button: {}", word)
+            }
+        }
+        inputs.push((start, controls));
+    }
+    inputs.sort_by_key(|&(start, _)| start);
+    inputs
+}
+
+fn report(map: &Map, ticks: u64) {
+    println!("After {} ticks:", ticks);
+    for (handle, &(tile, ref object)) in &map.objects {
+        println!(
+            "{}: tile {} at ({}, {}), rotation: {}, flipped: {}, on_ground: {}, on_wall: {}",
+            handle.0, tile, object.x, object.y, object.rotation, object.flipped, object.on_ground, object.on_wall
+        );
+        if object.frozen {
+            println!("{}: frozen by a script error", handle.0);
+        }
+    }
+    if let Some(ref error) = map.last_error {
+        println!("Last script error: {}", error);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tests::load_finite;
+
+    ///Runs Finite.tmx with extra players dropped onto each other, so the order objects update in matters,
+    ///either recording to `path` or replaying from it
+    fn run_players(path: &str, record: bool) -> bool {
+        let lua = Lua::new();
+        let (tiles, mut map) = load_finite(&lua);
+        let tick = 1.0 / map.tick_rate;
+        set_globals(&lua, tick);
+        lua.globals().set("world", map.world()).unwrap();
+        lua.exec::<()>("for i = 1, 4 do world:spawn('walktest12-0', 420 + 40 * i, 800 - 240 * i) end", None).unwrap();
+        let inputs = vec![
+            (0, Controls { right: true, ..Controls::default() }),
+            (60, Controls { left: true, a: true, ..Controls::default() }),
+        ];
+        if record {
+            let mut recorder = Recorder::create(path, tick);
+            simulate(&lua, &tiles, &mut map, tick, 120, inputs, Some(&mut recorder), None)
+        } else {
+            let mut replay = Replay::load(path);
+            assert_eq!(replay.len(), 120);
+            simulate(&lua, &tiles, &mut map, tick, 120, Vec::new(), None, Some(&mut replay))
+        }
+    }
+
+    #[test]
+    fn replay_matches_recording() {
+        let path = std::env::temp_dir().join("lgj2018-replay-test.txt");
+        let path = path.to_str().unwrap();
+        assert!(!run_players(path, true));
+        assert!(!run_players(path, false), "Replay diverged from its recording");
+    }
+}
@@ -10,44 +10,91 @@ extern crate palette;
 extern crate ncollide;
 extern crate nalgebra;
 
-use std::collections::{HashMap, HashSet};
+mod camera;
+mod debug;
+mod headless;
+mod replay;
+mod reload;
+mod render;
+mod text;
+
+use std::collections::{HashMap, HashSet, BTreeMap, BTreeSet};
 use std::io::Read;
 use std::time::Instant;
 use std::sync::Arc;
 use indexmap::IndexMap;
-use gfx::traits::{Factory, FactoryExt};
+use gfx::traits::FactoryExt;
 use gfx::handle::ShaderResourceView;
+use gfx::memory::Typed;
 use glutin::GlContext;
 use rlua::{Lua, UserData, UserDataMethods, MetaMethod};
 use tiled::Tileset;
-use nalgebra::{Vector2, Isometry2};
-use ncollide::{events::ContactEvent, shape::{ShapeHandle2, Cuboid2, Plane2}, world::{CollisionWorld2, CollisionGroups, GeometricQueryType, CollisionObjectHandle}};
+use nalgebra::{Vector2, Point2, Isometry2};
+use ncollide::{shape::{ShapeHandle2, Cuboid2, Plane2, Ball2, Compound2, ConvexHull2}, world::{CollisionWorld2, CollisionGroups, GeometricQueryType, CollisionObjectHandle}, query::Proximity};
+use rlua::ToLuaMulti;
 
 pub type ColorFormat = gfx::format::Srgba8;
 pub type DepthFormat = gfx::format::DepthStencil;
 pub type TextureColorFormat = (gfx::format::R8, gfx::format::Uint);
 
-const INDICES: &[u16] = &[0, 1, 2, 2, 3, 0];
+const INDICES: &[u32] = &[0, 1, 2, 2, 3, 0];
 
 gfx_defines! {
     vertex Vertex {
         pos: [f32; 2] = "pos",
         uv: [f32; 2] = "uv",
+        //Row of the palette texture to colour the sprite with
+        palette: f32 = "palette_row",
     }
     pipeline pipe {
         vbuf: gfx::VertexBuffer<Vertex> = (),
         palette: gfx::TextureSampler<[f32; 4]> = "palette",
         sprite: gfx::TextureSampler<u32> = "sprite",
-        x: gfx::Global<i32> = "x",
-        y: gfx::Global<i32> = "y",
+        camera: gfx::Global<[f32; 2]> = "camera",
+        zoom: gfx::Global<f32> = "zoom",
+        rotation: gfx::Global<f32> = "rotation",
+        width: gfx::Global<f32> = "width",
+        height: gfx::Global<f32> = "height",
+        out: gfx::BlendTarget<ColorFormat> = ("Target0", gfx::state::ColorMask::all(), gfx::preset::blend::ALPHA),
+    }
+    vertex BlitVertex {
+        pos: [f32; 2] = "pos",
+        uv: [f32; 2] = "uv",
+    }
+    //Copies the offscreen view into the window
+    pipeline blit {
+        vbuf: gfx::VertexBuffer<BlitVertex> = (),
+        screen: gfx::TextureSampler<[f32; 4]> = "screen",
+        out: gfx::RenderTarget<ColorFormat> = "Target0",
+    }
+    vertex DebugVertex {
+        pos: [f32; 2] = "pos",
+        color: [f32; 4] = "color",
+    }
+    //Coloured lines in the world for the debug overlay, seen through the same camera as the sprites
+    pipeline lines {
+        vbuf: gfx::VertexBuffer<DebugVertex> = (),
+        camera: gfx::Global<[f32; 2]> = "camera",
+        zoom: gfx::Global<f32> = "zoom",
+        rotation: gfx::Global<f32> = "rotation",
         width: gfx::Global<f32> = "width",
         height: gfx::Global<f32> = "height",
-        flip: gfx::Global<f32> = "flip",
         out: gfx::BlendTarget<ColorFormat> = ("Target0", gfx::state::ColorMask::all(), gfx::preset::blend::ALPHA),
     }
 }
 
 fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let (args, record, mut replay) = replay::parse_args(&args[1..]);
+    if args.get(0).map(|arg| arg.as_str()) == Some("--headless") {
+        headless::run(&args[1..], record, replay);
+        return
+    }
+    if args.get(0).map(|arg| arg.as_str()) == Some("--render") {
+        render::run(&args[1..]);
+        return
+    }
+
     let instant = Instant::now();
     let mut events_loop = glutin::EventsLoop::new();
     let context = glutin::ContextBuilder::new()
@@ -56,53 +103,19 @@ fn main() {
         .with_title("Umbrella is a verb")
         .with_dimensions(768, 768);
 
-    let width = 2048;
-    let height = 2048;
-
-    let (window, mut device, mut factory, main_color, mut main_depth) =
+    let (window, mut device, mut factory, mut main_color, mut main_depth) =
         gfx_window_glutin::init::<ColorFormat, DepthFormat>(builder, context, &events_loop);
 
     let mut encoder: gfx::Encoder<_, _> = factory.create_command_buffer().into();
-    let pso = factory.create_pipeline_simple(
-        include_bytes!("shaders/shader.vert"),
-        include_bytes!("shaders/shader.frag"),
-        pipe::new(),
-    ).unwrap();
-
-    let slice = gfx::Slice {
-        start: 0,
-        end: 8,
-        base_vertex: 0,
-        instances: None,
-        buffer: factory.create_index_buffer(INDICES),
-    };
-
-    let palette_sampler = factory.create_sampler(gfx::texture::SamplerInfo::new(gfx::texture::FilterMethod::Scale, gfx::texture::WrapMode::Clamp));
-    let sampler = factory.create_sampler(gfx::texture::SamplerInfo::new(gfx::texture::FilterMethod::Scale, gfx::texture::WrapMode::Tile));
-
-    let mut graphics = Graphics::new(factory);
 
     let lua = Lua::new();
 
+    let map_filename = "assets/tiled/Finite.tmx";
     let mut tiles = Tiles::new(&lua);
-    let mut map = Map::load(&mut graphics, &mut tiles, "assets/tiled/Finite.tmx");
-
-    let mut data = {
-        let tile = &tiles.tiles[0];
-        let texture = graphics.get_texture(tile.texture);
-
-        pipe::Data {
-            vbuf: texture.vertex_buffers[0].clone(),
-            palette: (graphics.get_palette(texture.palette).get(0), palette_sampler),
-            sprite: (texture.texture.clone(), sampler),
-            x: 0,
-            y: 0,
-            width: width as f32,
-            height: height as f32,
-            flip: 1.0,
-            out: main_color,
-        }
-    };
+    let mut map = Map::load(&mut tiles, map_filename).unwrap_or_else(|error| panic!("{}", error));
+    let mut renderer = Renderer::new(factory, &tiles);
+    let mut watcher = reload::Watcher::new();
+    watcher.watch_resources(map_filename, &map, &tiles, &renderer.graphics);
 
     let elapsed = instant.elapsed();
     println!("Loaded in {}", elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1.0e9);
@@ -110,12 +123,17 @@ fn main() {
     let mut controls = Controls::default();
 
     let globals = lua.globals();
-    globals.set("controls", controls).unwrap();
-    globals.set("gravity", 500.0).unwrap();
+    let tick = replay.as_ref().map_or(1.0 / map.tick_rate, |replay| replay.delta);
+    set_globals(&lua, tick);
+    let mut recorder = record.map(|path| replay::Recorder::create(&path, tick));
 
     let mut running = true;
     let mut instant = Instant::now();
     let mut counter = 0.0;
+    let mut accumulator = 0.0;
+    let mut screenshot = false;
+    let mut debug = false;
+    let mut title_error = None;
     
     while running {
         events_loop.poll_events(|event| {
@@ -126,7 +144,7 @@ fn main() {
                     match event {
                         Closed => running = false,
                         Resized(_, _) => {
-                            gfx_window_glutin::update_views(&window, &mut data.out, &mut main_depth)
+                            gfx_window_glutin::update_views(&window, &mut main_color, &mut main_depth)
                         }
                         KeyboardInput { device_id: _, input } => {
                             use glutin::{ElementState, VirtualKeyCode::*};
@@ -138,13 +156,18 @@ fn main() {
                                 Some(Right) => controls.right = pressed,
                                 Some(Up) => controls.up = pressed,
                                 Some(Down) => controls.down = pressed,
+                                Some(F1) if pressed => debug = !debug,
+                                Some(F2) if pressed => renderer.graphics.dump_atlases(),
+                                Some(F3) if pressed => {
+                                    map.scaling = map.scaling.next();
+                                    println!("Scaling: {:?}", map.scaling);
+                                }
+                                Some(F12) if pressed => screenshot = true,
                                 _ => ()
                             }
-                            globals.set("controls", controls).unwrap();
                         }
                         Focused(false) => {
                             controls = Controls::default();
-                            globals.set("controls", controls).unwrap();
                         }
                         _ => ()
                     }
@@ -152,100 +175,104 @@ fn main() {
                 _ => ()
             }
         });
+        let changed = watcher.poll();
+        for path in &changed {
+            println!("Reloading {}", path);
+            if path.ends_with(".lua") {
+                tiles.reload_script(path);
+                map.reload_script(&lua, &tiles, path);
+            } else if path.ends_with(".tmx") || path.ends_with(".tsx") {
+                //Embedded tilesets change with the map, so every tileset is read again
+                tiles.reload_tilesets();
+                //Keeps playing the old map until the file loads again
+                let graphics = &mut renderer.graphics;
+                match Map::load_checked(&mut tiles, map_filename, |tiles| graphics.load_tiles(tiles)) {
+                    Ok(loaded) => map = loaded,
+                    Err(error) => {
+                        println!("{}", error);
+                        map.last_error = Some(error);
+                    }
+                }
+            } else {
+                renderer.graphics.reload(path);
+            }
+        }
+        if !changed.is_empty() {
+            watcher.watch_resources(map_filename, &map, &tiles, &renderer.graphics);
+        }
         let elapsed = instant.elapsed();
         instant = Instant::now();
         let delta = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1.0e9;
-        counter += delta;
-        globals.set("delta", delta).expect("Unable to set delta");
-        encoder.clear(&data.out, map.color);
-        for ((x, y), map_tile) in &map.map {
-            let mut tile = tiles.get(map_tile.tile);
-            
-            if !tile.animation.is_empty() {
-                let frame = (counter / (tile.animation[0].duration as f64 / 1000.0)) as usize % tile.animation.len();
-                tile = &tiles.tiles[tile.animation[frame].tile];
-            }
-            
-            let texture = graphics.get_texture(tile.texture);
-            data.sprite.0 = texture.texture.clone();
-            data.x = x * 256;
-            data.y = y * 256;
-            data.flip = if map_tile.flipped {-1.0} else {1.0};
-            data.palette.0 = graphics.get_palette(texture.palette).get(0);
-            data.vbuf = texture.vertex_buffers[map_tile.rotation].clone();
-            encoder.draw(&slice, &pso, &data);
-        }
-        for (handle, &mut (tile, ref mut object)) in &mut map.objects {
-            let mut tile = tiles.get(tile);
-            globals.set("object", object.clone()).expect("Unable to set object");
-            if let Some(ref script) = tile.script {
-                script.call::<(), ()>(()).expect("Script errored");
-                lua.eval::<()>("update()", Some("update")).expect("Update failed");
-                *object = globals.get("object").expect("Object vanished!");
-            }
-            
-            {
-                if object.move_x != 0.0 || object.move_y != 0.0 {
-                    let (object_shape, object_position) = {
-                        let collision_object = map.world.collision_object(*handle).unwrap();
-                        (collision_object.shape().clone(), collision_object.position().clone())
-                    };
-                    map.world.set_position(*handle, Isometry2::new(Vector2::new(object.x + object.move_x, object.y + object.move_y), nalgebra::zero()));
-                    map.world.update();
-                    let mut time = 1.0;
-                    for contact_event in map.world.contact_events() {
-                        let collided = match contact_event {
-                            ContactEvent::Started(a, b) if a == handle => Some(b),
-                            ContactEvent::Started(a, b) if b == handle => Some(a),
-                            _ => None
-                        };
-                        if let Some(collided) = collided {
-                            let other_collider = map.world.collision_object(*collided).unwrap();
-                            let new_time = ncollide::query::time_of_impact(
-                                &object_position, &Vector2::new(object.move_x, object.move_y), object_shape.as_ref(),
-                                other_collider.position(), &Vector2::new(0.0, 0.0), other_collider.shape().as_ref(),
-                            );
-                            match new_time {
-                                Some(new_time) if new_time < time => time = new_time,
-                                _ => ()
-                            }
-                            println!("Collided with {} {:?}", collided.0, new_time);
-                            println!("{:?}\n{:?}", object_position, other_collider.position());
-                            
-                        }
-                    }
-                    object.x += object.move_x * time;
-                    object.y += object.move_y * time;
-                    object.move_x = 0.0;
-                    object.move_y = 0.0;
-                    map.world.set_position(*handle, Isometry2::new(Vector2::new(object.x, object.y), nalgebra::zero()));
-                    map.world.update();
-                }
+        //Don't try to catch up on more than a quarter of a second at once
+        accumulator += delta.min(0.25);
+        while accumulator >= tick {
+            accumulator -= tick;
+            counter += tick;
+            let tick_controls = match replay.as_mut().and_then(|replay| replay.next()) {
+                Some(replayed) => replayed,
+                None => controls
+            };
+            globals.set("controls", tick_controls).unwrap();
+            map.tick(&lua, &tiles, counter);
+            let hash = map.state_hash();
+            if let Some(ref mut replay) = replay {
+                replay.check(hash);
             }
-
-            if !tile.animation.is_empty() {
-                let frame = (counter / (tile.animation[0].duration as f64 / 1000.0)) as usize % tile.animation.len();
-                tile = &tiles.tiles[tile.animation[frame].tile];
+            if let Some(ref mut recorder) = recorder {
+                recorder.record(tick_controls, hash);
             }
-
-            let texture = graphics.get_texture(tile.texture);
-            data.sprite.0 = texture.texture.clone();
-            data.x = object.x as i32;
-            data.y = object.y as i32;
-            data.flip = if object.flipped {-1.0} else {1.0};
-            data.palette.0 = graphics.get_palette(texture.palette).get(0);
-            data.vbuf = texture.vertex_buffers[object.rotation].clone();
-            encoder.draw(&slice, &pso, &data);
         }
+        //Maps without a font can't draw the error over the view, so it goes in the title bar instead
+        let error = if map.font.is_none() {map.last_error.clone()} else {None};
+        if error != title_error {
+            match error {
+                Some(ref error) => window.set_title(&format!("Umbrella is a verb - {}", error.lines().next().unwrap_or(""))),
+                None => window.set_title("Umbrella is a verb")
+            }
+            title_error = error;
+        }
+        //How far between the last tick and the next one this frame is
+        let alpha = accumulator / tick;
+        renderer.draw(&mut encoder, &lua, &map, &tiles, counter, alpha);
+        if debug {
+            renderer.draw_debug(&mut encoder, &map);
+        }
+        if screenshot {
+            screenshot = false;
+            let time = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or(0);
+            let path = format!("screenshot-{}.png", time);
+            match renderer.capture(&mut encoder, &mut device).save(&path) {
+                Ok(()) => println!("Wrote {}", path),
+                Err(error) => println!("Unable to write {}: {}", path, error)
+            }
+        }
+        let window_size = window.get_inner_size().unwrap_or((768, 768));
+        renderer.present(&mut encoder, &main_color, window_size, map.scaling, map.color);
         window.swap_buffers().unwrap();
         encoder.flush(&mut device);
     }
 }
 
+///Sets the globals every script expects before the first tick
+fn set_globals(lua: &Lua, tick: f64) {
+    let globals = lua.globals();
+    globals.set("controls", Controls::default()).unwrap();
+    globals.set("gravity", 500.0).unwrap();
+    globals.set("delta", tick).expect("Unable to set delta");
+}
+
 #[derive(Clone)]
-struct Object {
+pub struct Object {
+    ///The object's handle in the collision world and `Map::objects`
+    handle: CollisionObjectHandle,
+    ///Counts up with every object spawned, since handles are reused once their object is destroyed
+    generation: u64,
     x: f64,
     y: f64,
+    ///x at the start of the current tick, for interpolating between ticks when drawing
+    last_x: f64,
+    ///y at the start of the current tick
+    last_y: f64,
     ///x to attempt to move in the movement step
     move_x: f64,
     ///y to attempt to move in the movement step
@@ -254,32 +281,206 @@ struct Object {
     height: f64,
     rotation: usize,
     flipped: bool,
+    ///Row of the palette the object is drawn with
+    palette: usize,
+    ///Whether the object is standing on something, relative to its rotation
+    on_ground: bool,
+    ///Whether the object is pushing against something to its left or right, relative to its rotation
+    on_wall: bool,
+    ///Normals of everything the object is touching after the movement step, pointing towards the object
+    normals: Vec<Vector2<f64>>,
     key: Arc<rlua::RegistryKey>,
+    ///The object's own environment for its script, holding `init`, `update` and its other callbacks
+    env: Option<Arc<rlua::RegistryKey>>,
+    ///Set when the object's script fails, after which the object stays where it is and its script isn't called again
+    frozen: bool,
+    ///The tile, rotation and flip the object's collision shape was built from
+    shape: (usize, usize, bool),
+    ///Set on the copies world queries hand out, which can be read and destroyed but not moved or changed
+    view: bool,
 }
 
 impl Object {
-    fn new(lua: &Lua, tile: &Tile, x: f64, y: f64, rotation: usize, flipped: bool) -> Object {
+    fn new(lua: &Lua, tile: &Tile, handle: CollisionObjectHandle, generation: u64, x: f64, y: f64, rotation: usize, flipped: bool, shape: usize) -> Object {
         let key = Arc::new(lua.create_registry_value(lua.create_table().unwrap()).unwrap());
-        let object = Object {
+        Object {
+            handle,
+            generation,
             x,
             y,
+            last_x: x,
+            last_y: y,
             move_x: 0.0,
             move_y: 0.0,
             width: tile.width as f64,
             height: tile.height as f64,
             rotation,
             flipped,
+            palette: tile.palette_id,
+            on_ground: false,
+            on_wall: false,
+            normals: Vec::new(),
             key,
-        };
+            env: None,
+            frozen: false,
+            shape: (shape, rotation, flipped),
+            view: false,
+        }
+    }
+
+    ///Loads the tile's script into a new environment for the object and runs its `init`, returning any error
+    fn init(&mut self, lua: &Lua, handle: CollisionObjectHandle, tile: &Tile) -> Option<String> {
         if let Some(ref script) = tile.script {
-            lua.globals().set("object", object).expect("Failed to set object global");
-            script.call::<(), ()>(()).expect("Script failed");
-            lua.eval::<()>("init()", Some("init")).expect("Init failed");
-            lua.globals().get("object").expect("Failed to retrieve object")
+            match script.load(lua) {
+                Ok(env) => self.env = Some(Arc::new(env)),
+                Err(error) => return Some(self.fail(handle, tile, "load", error))
+            }
+        }
+        self.call_logged(lua, handle, tile, "init", ())
+    }
+
+    ///Like `call`, but a failure freezes the object and is logged instead of returned, with the message given back for showing on screen
+    fn call_logged<'lua, A: ToLuaMulti<'lua>>(&mut self, lua: &'lua Lua, handle: CollisionObjectHandle, tile: &Tile, function: &str, args: A) -> Option<String> {
+        if self.frozen {
+            return None
+        }
+        match self.call(lua, function, args) {
+            Ok(()) => None,
+            Err(error) => Some(self.fail(handle, tile, function, error))
+        }
+    }
+
+    fn fail(&mut self, handle: CollisionObjectHandle, tile: &Tile, function: &str, error: rlua::Error) -> String {
+        self.frozen = true;
+        let path = tile.script.as_ref().map_or("<no script>", |script| script.path.as_str());
+        let message = format!("{} failed for object {} ({}): {}", function, handle.0, path, error);
+        println!("Script error, freezing object: {}", message);
+        message
+    }
+
+    ///Swaps the collision shape for the one the object's rotation, flip and animation frame call for, if that changed
+    fn update_shape(&mut self, world: &mut CollisionWorld2<f64, Collider>, handle: CollisionObjectHandle, tiles: &Tiles, tile_id: usize, counter: f64) {
+        let shape = (tiles.hitbox_tile(tile_id, counter), self.rotation, self.flipped);
+        if shape == self.shape {
+            return
+        }
+        self.shape = shape;
+        let (position, groups, query_type) = {
+            let collision_object = world.collision_object(handle).unwrap();
+            (*collision_object.position(), *collision_object.collision_groups(), collision_object.query_type())
+        };
+        //ncollide can't change a shape in place, but gives the slot just freed straight back, so the handle stays the same
+        world.remove(&[handle]);
+        let new_handle = world.add(position, tiles.get(shape.0).hitbox(shape.1, shape.2), groups, query_type, Collider::Object);
+        assert_eq!(new_handle, handle, "Collision object moved to a new handle when changing shape");
+    }
+
+    ///Moves by `move_x` and `move_y`, sliding along anything solid in the way instead of stopping at it
+    fn slide(&mut self, world: &mut CollisionWorld2<f64, Collider>, handle: CollisionObjectHandle) {
+        ///Distance kept between the object and whatever it hits, so it can slide along it afterwards
+        const SKIN: f64 = 0.01;
+        const ITERATIONS: usize = 4;
+        use ncollide::query;
+        let shape = world.collision_object(handle).unwrap().shape().clone();
+        let mut position = Vector2::new(self.x, self.y);
+        let mut motion = Vector2::new(self.move_x, self.move_y);
+        for _ in 0..ITERATIONS {
+            let length = motion.norm();
+            if length < SKIN {
+                break
+            }
+            let start = Isometry2::new(position, nalgebra::zero());
+            let mut time = 1.0;
+            let mut normal = None;
+            for other in world.collision_objects() {
+                if other.handle() == handle || !other.data().is_solid() {
+                    continue
+                }
+                let new_time = match query::time_of_impact(&start, &motion, shape.as_ref(), other.position(), &nalgebra::zero(), other.shape().as_ref()) {
+                    Some(new_time) if new_time < time => new_time,
+                    _ => continue
+                };
+                let impact = Isometry2::new(position + motion * new_time, nalgebra::zero());
+                let new_normal = query::contact(&impact, shape.as_ref(), other.position(), other.shape().as_ref(), SKIN * 2.0)
+                    .map_or(-motion / length, |contact| -contact.normal.unwrap());
+                //Already touching but moving away, like jumping off the floor
+                if motion.dot(&new_normal) >= 0.0 {
+                    continue
+                }
+                time = new_time;
+                normal = Some(new_normal);
+            }
+            match normal {
+                Some(normal) => {
+                    position += motion * (time - (SKIN / length).min(time));
+                    let leftover = motion * (1.0 - time);
+                    motion = leftover - normal * leftover.dot(&normal);
+                }
+                None => {
+                    position += motion;
+                    break
+                }
+            }
+        }
+        self.x = position.x;
+        self.y = position.y;
+        self.move_x = 0.0;
+        self.move_y = 0.0;
+
+        let end = Isometry2::new(position, nalgebra::zero());
+        self.normals = world.collision_objects()
+            .filter(|other| other.handle() != handle && other.data().is_solid())
+            .filter_map(|other| query::contact(&end, shape.as_ref(), other.position(), other.shape().as_ref(), SKIN * 2.0))
+            .map(|contact| -contact.normal.unwrap())
+            .collect();
+        let (right, down) = self.axes();
+        self.on_ground = self.normals.iter().any(|normal| normal.dot(&down) < -0.7);
+        self.on_wall = self.normals.iter().any(|normal| normal.dot(&right).abs() > 0.7);
+        world.set_position(handle, end);
+    }
+
+    ///Left, top, right and bottom edges of the object as drawn
+    fn bounds(&self) -> (f64, f64, f64, f64) {
+        let (width, height) = if self.rotation % 2 == 0 {
+            (self.width, self.height)
         } else {
-            object
+            (self.height, self.width)
+        };
+        (self.x, self.y, self.x + width, self.y + height)
+    }
+
+    ///The directions of the object's right and down in world space, following its rotation
+    fn axes(&self) -> (Vector2<f64>, Vector2<f64>) {
+        match self.rotation {
+            0 => (Vector2::new(1.0, 0.0), Vector2::new(0.0, 1.0)),
+            1 => (Vector2::new(0.0, 1.0), Vector2::new(-1.0, 0.0)),
+            2 => (Vector2::new(-1.0, 0.0), Vector2::new(0.0, -1.0)),
+            _ => (Vector2::new(0.0, -1.0), Vector2::new(1.0, 0.0))
+        }
+    }
+
+    ///Fails if this is a copy from a world query, since changing it wouldn't change the real object
+    fn check_writable(&self, method: &str) -> rlua::Result<()> {
+        if self.view {
+            Err(rlua::Error::RuntimeError(format!("Can't {} object {} through a world query, which only gives a copy", method, self.handle.0)))
+        } else {
+            Ok(())
         }
     }
+
+    ///Calls the named function from the object's script if it has one
+    fn call<'lua, A: ToLuaMulti<'lua>>(&mut self, lua: &'lua Lua, function: &str, args: A) -> rlua::Result<()> {
+        let env: rlua::Table = match self.env {
+            Some(ref env) => lua.registry_value(env)?,
+            None => return Ok(())
+        };
+        if let Some(function) = env.get::<_, Option<rlua::Function>>(function)? {
+            env.set("object", self.clone())?;
+            function.call::<_, ()>(args)?;
+            *self = env.get("object")?;
+        }
+        Ok(())
+    }
 }
 
 impl UserData for Object {
@@ -287,6 +488,7 @@ impl UserData for Object {
         use rlua::Value;
         //TODO Make this relative
         methods.add_method_mut("move", |_, object, (x, y): (f64, f64)| {
+            object.check_writable("move")?;
             let (x, y) = match object.rotation {
                 0 => (x, y),
                 1 => (-y, x),
@@ -298,13 +500,26 @@ impl UserData for Object {
             Ok(())
         });
         methods.add_method_mut("rotate", |_, object, rotation: i64| {
+            object.check_writable("rotate")?;
             object.rotation = ((rotation % 4) + 4) as usize % 4;
             Ok(())
         });
         methods.add_method_mut("flip", |_, object, flipped: bool| {
+            object.check_writable("flip")?;
             object.flipped = flipped;
             Ok(())
         });
+        methods.add_method_mut("set_palette", |_, object, palette: usize| {
+            object.check_writable("set_palette")?;
+            object.palette = palette;
+            Ok(())
+        });
+        //Kept in the registry so destroying a copy of the object, like the other object in on_hit, still works.
+        //The generation stops a copy kept after its object is gone from destroying whatever took its handle.
+        methods.add_method("destroy", |lua, object, ()| {
+            let destroyed: rlua::Table = lua.named_registry_value("destroyed")?;
+            destroyed.set(object.handle.0, object.generation)
+        });
         methods.add_meta_method(MetaMethod::ToString, |_, object, ()| {
             Ok(format!("x: {}\ny: {}, rotation: {}", object.x, object.y, object.rotation))
         });
@@ -332,6 +547,21 @@ impl UserData for Object {
                 "height" => Value::Number(object.height),
                 "rotation" => Value::Integer(object.rotation as i64),
                 "flipped" => Value::Boolean(object.flipped),
+                "palette" => Value::Integer(object.palette as i64),
+                "handle" => Value::Integer(object.handle.0 as i64),
+                "on_ground" => Value::Boolean(object.on_ground),
+                "on_wall" => Value::Boolean(object.on_wall),
+                "normals" => {
+                    let (right, down) = object.axes();
+                    let normals = lua.create_table()?;
+                    for (i, normal) in object.normals.iter().enumerate() {
+                        let table = lua.create_table()?;
+                        table.set("x", normal.dot(&right))?;
+                        table.set("y", normal.dot(&down))?;
+                        normals.set(i + 1, table)?;
+                    }
+                    Value::Table(normals)
+                }
                 index => {
                     lua.registry_value::<rlua::Table>(&object.key).unwrap().get(index).unwrap_or(Value::Nil)
                 }
@@ -345,7 +575,7 @@ impl UserData for Object {
 }
 
 #[derive(Default, Debug, Copy, Clone)]
-struct Controls {
+pub struct Controls {
     pub up: bool,
     pub down: bool,
     pub left: bool,
@@ -354,6 +584,25 @@ struct Controls {
     pub b: bool,
 }
 
+impl Controls {
+    fn bits(&self) -> u8 {
+        [self.up, self.down, self.left, self.right, self.a, self.b].iter()
+            .enumerate()
+            .fold(0, |bits, (i, &pressed)| if pressed {bits | 1 << i} else {bits})
+    }
+
+    fn from_bits(bits: u8) -> Controls {
+        Controls {
+            up: bits & 1 != 0,
+            down: bits & 2 != 0,
+            left: bits & 4 != 0,
+            right: bits & 8 != 0,
+            a: bits & 16 != 0,
+            b: bits & 32 != 0,
+        }
+    }
+}
+
 impl UserData for Controls {
     fn add_methods(methods: &mut UserDataMethods<Self>) {
         methods.add_meta_method(MetaMethod::ToString, |_, controls, ()| {
@@ -376,49 +625,354 @@ impl UserData for Controls {
     }
 }
 
+///What scripts can see of the map through the `world` global, made again at the start of every tick.
+///Positions here are in map pixels, not relative to an object's rotation.
+#[derive(Clone)]
 struct World {
-    map: Arc<Map>
+    map: Arc<MapInfo>,
+    layers: Arc<Vec<Layer>>,
+    ///Every object with its movement hitbox, as they were at the start of the tick. The objects are read-only copies,
+    ///though their state tables are shared with the real ones.
+    objects: Vec<(Object, ShapeHandle2<f64>)>,
+}
+
+impl World {
+    fn objects_where<'lua, F: Fn(&Object) -> bool>(&self, lua: &'lua Lua, filter: F) -> rlua::Result<rlua::Table<'lua>> {
+        let table = lua.create_table()?;
+        for (i, object) in self.objects.iter().map(|&(ref object, _)| object).filter(|object| filter(object)).enumerate() {
+            table.set(i + 1, object.clone())?;
+        }
+        Ok(table)
+    }
+
+    ///The closest solid shape along the ray, skipping the object with the handle `ignore`
+    fn raycast<'lua>(&self, lua: &'lua Lua, origin: Point2<f64>, direction: Vector2<f64>, max_distance: f64, ignore: Option<usize>) -> rlua::Result<rlua::Value<'lua>> {
+        use ncollide::query::Ray2;
+        let ray = Ray2::new(origin, direction.normalize());
+        //The object hit is kept as its index in `objects`
+        let mut closest: Option<(f64, Vector2<f64>, Option<usize>)> = None;
+        {
+            let mut cast = |position: &Isometry2<f64>, shape: &ShapeHandle2<f64>, object: Option<usize>| {
+                if let Some(ray_cast) = shape.as_ray_cast() {
+                    if let Some(hit) = ray_cast.toi_and_normal_with_ray(position, &ray, true) {
+                        if hit.toi <= max_distance && closest.map_or(true, |(toi, _, _)| hit.toi < toi) {
+                            closest = Some((hit.toi, hit.normal, object));
+                        }
+                    }
+                }
+            };
+            for &(ref position, ref shape) in &self.map.shapes {
+                cast(position, shape, None);
+            }
+            for (i, &(ref object, ref shape)) in self.objects.iter().enumerate() {
+                if Some(object.handle.0) != ignore {
+                    cast(&Isometry2::new(Vector2::new(object.x, object.y), nalgebra::zero()), shape, Some(i));
+                }
+            }
+        }
+        Ok(match closest {
+            Some((toi, normal, object)) => {
+                let point = ray.origin + ray.dir * toi;
+                let hit = lua.create_table()?;
+                hit.set("x", point.x)?;
+                hit.set("y", point.y)?;
+                hit.set("distance", toi)?;
+                hit.set("normal_x", normal.x)?;
+                hit.set("normal_y", normal.y)?;
+                if let Some(object) = object {
+                    hit.set("object", self.objects[object].0.clone())?;
+                }
+                rlua::Value::Table(hit)
+            }
+            None => rlua::Value::Nil
+        })
+    }
 }
 
 impl UserData for World {
+    fn add_methods(methods: &mut UserDataMethods<Self>) {
+        use rlua::Value;
+        //Looks in the named layer, or else the topmost layer with a tile there
+        methods.add_method("tile_at", |lua, world, (x, y, layer): (f64, f64, Option<String>)| {
+            let column = (x / world.map.tile_width as f64).floor() as i32;
+            let row = (y / world.map.tile_height as f64).floor() as i32;
+            let found = world.layers.iter().rev()
+                .filter(|found| layer.as_ref().map_or(true, |name| *name == found.name))
+                .filter_map(|found| found.tiles.get(&(column, row)).map(|map_tile| (found, map_tile)))
+                .next();
+            Ok(match found {
+                Some((found, map_tile)) => {
+                    let table = lua.create_table()?;
+                    table.set("id", map_tile.tile)?;
+                    table.set("layer", found.name.as_str())?;
+                    table.set("solid", found.solid)?;
+                    table.set("column", column)?;
+                    table.set("row", row)?;
+                    table.set("rotation", map_tile.rotation)?;
+                    table.set("flipped", map_tile.flipped)?;
+                    Value::Table(table)
+                }
+                None => Value::Nil
+            })
+        });
+        methods.add_method("objects_in_rect", |lua, world, (x, y, width, height): (f64, f64, f64, f64)| {
+            world.objects_where(lua, |object| {
+                let (left, top, right, bottom) = object.bounds();
+                left < x + width && right > x && top < y + height && bottom > y
+            })
+        });
+        methods.add_method("objects_in_radius", |lua, world, (x, y, radius): (f64, f64, f64)| {
+            world.objects_where(lua, |object| {
+                let (left, top, right, bottom) = object.bounds();
+                let dx = x - x.max(left).min(right);
+                let dy = y - y.max(top).min(bottom);
+                dx * dx + dy * dy <= radius * radius
+            })
+        });
+        methods.add_method("raycast", |lua, world, (x, y, dx, dy, max_distance, ignore): (f64, f64, f64, f64, Option<f64>, Option<Object>)| {
+            world.raycast(lua, Point2::new(x, y), Vector2::new(dx, dy), max_distance.unwrap_or(std::f64::INFINITY), ignore.map(|object| object.handle.0))
+        });
+        methods.add_method("spawn", |lua, _, (tile, x, y, properties): (Value, f64, f64, Option<rlua::Table>)| {
+            let spawn = lua.create_table()?;
+            spawn.set("tile", tile)?;
+            spawn.set("x", x)?;
+            spawn.set("y", y)?;
+            spawn.set("properties", properties)?;
+            let spawns: rlua::Table = lua.named_registry_value("spawns")?;
+            spawns.set(spawns.len()? + 1, spawn)?;
+            Ok(())
+        });
+        methods.add_meta_method(MetaMethod::Index, |lua, world, index: String| {
+            let map = &world.map;
+            Ok(match index.as_str() {
+                "width" => Value::Number((map.columns * map.tile_width) as f64),
+                "height" => Value::Number((map.rows * map.tile_height) as f64),
+                "columns" => Value::Integer(map.columns as i64),
+                "rows" => Value::Integer(map.rows as i64),
+                "tile_width" => Value::Integer(map.tile_width as i64),
+                "tile_height" => Value::Integer(map.tile_height as i64),
+                "background" => {
+                    let table = lua.create_table()?;
+                    table.set("r", map.background[0])?;
+                    table.set("g", map.background[1])?;
+                    table.set("b", map.background[2])?;
+                    Value::Table(table)
+                }
+                "properties" => Value::Table(properties_table(lua, &map.properties)?),
+                _ => Value::Nil
+            })
+        });
+    }
+}
+
+///Map data that doesn't change while it runs, shared with every `World`
+pub struct MapInfo {
+    columns: u32,
+    rows: u32,
+    tile_width: u32,
+    tile_height: u32,
+    ///Background colour as sRGB bytes
+    background: [u8; 3],
+    properties: tiled::Properties,
+    ///Every solid map shape, including the edges, for raycasts
+    shapes: Vec<(Isometry2<f64>, ShapeHandle2<f64>)>,
+}
+
+///Turns Tiled properties into a Lua table
+fn properties_table<'lua>(lua: &'lua Lua, properties: &tiled::Properties) -> rlua::Result<rlua::Table<'lua>> {
+    use tiled::PropertyValue::*;
+    use rlua::Value;
+    let table = lua.create_table()?;
+    for (name, value) in properties {
+        let value = match value {
+            BoolValue(v) => Value::Boolean(*v),
+            FloatValue(v) => Value::Number(*v as f64),
+            IntValue(v) => Value::Integer(*v as i64),
+            ColorValue(v) => Value::Integer(*v as i64),
+            StringValue(v) | FileValue(v) => Value::String(lua.create_string(v)?),
+        };
+        table.set(name.as_str(), value)?;
+    }
+    Ok(table)
+}
+
+struct Layer {
+    name: String,
+    visible: bool,
+    ///Whether the layer's tiles block movement, from the `solid` layer property
+    solid: bool,
+    ///Whether the layer is drawn in front of objects, from the `foreground` layer property
+    foreground: bool,
+    tiles: HashMap<(i32, i32), MapTile>,
 }
 
 struct MapTile {
     tile: usize,
     rotation: usize,
     flipped: bool,
+    ///Row of the palette the tile is drawn with
+    palette: usize,
+}
+
+///What a collision object in the world belongs to
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Collider {
+    Map,
+    Object,
+    ///Where the object with this handle can be hit
+    Hitbox(CollisionObjectHandle),
+    ///Where the object with this handle hits others
+    Hurtbox(CollisionObjectHandle),
+    ///A region from a Tiled object without a tile, which only reports objects entering and leaving it
+    Trigger,
+}
+
+impl Collider {
+    ///Whether objects are stopped by this in the movement step
+    fn is_solid(&self) -> bool {
+        match *self {
+            Collider::Map | Collider::Object => true,
+            _ => false
+        }
+    }
+}
+
+///A region that scripts hear about objects entering and leaving
+struct Trigger {
+    ///Path of the trigger's own script, for error messages
+    path: Option<String>,
+    ///The table scripts see for this trigger, with its Tiled name, type, bounds and properties
+    table: rlua::RegistryKey,
+    ///Environment of the trigger's script, if it has one that loaded
+    env: Option<rlua::RegistryKey>,
+}
+
+///Combat shapes for an object, kept in their own collision groups so they never block movement
+struct Combat {
+    ///The animation frame, rotation and flip the shapes were made for
+    frame: (usize, usize, bool),
+    ///The tile the hitbox was taken from, which differs from the frame when the frame has no Hitbox objects
+    hitbox_tile: usize,
+    hitbox: CollisionObjectHandle,
+    hurtbox: Option<CollisionObjectHandle>,
 }
 
-struct Map {
-    map: HashMap<(i32, i32), MapTile>,
-    objects: HashMap<CollisionObjectHandle, (usize, Object)>,
+pub struct Map {
+    ///Tile layers in the order they're drawn
+    layers: Arc<Vec<Layer>>,
+    info: Arc<MapInfo>,
+    ///Tile index for each gid in the map's tilesets
+    tile_lookup: HashMap<u32, usize>,
+    ///Simulation steps per second
+    tick_rate: f64,
+    ///How the view is scaled to fit the window
+    scaling: Scaling,
+    ///Kept in handle order, so objects update in the same order every run and replays match their recordings
+    objects: BTreeMap<CollisionObjectHandle, (usize, Object)>,
+    combat: BTreeMap<CollisionObjectHandle, Combat>,
+    ///(attacker, victim) pairs whose hurtbox and hitbox overlapped at the last update, so each overlap hits once
+    hits: BTreeSet<(CollisionObjectHandle, CollisionObjectHandle)>,
+    triggers: BTreeMap<CollisionObjectHandle, Trigger>,
+    ///(trigger, object) pairs that overlapped at the last update, so swapping an object's shape doesn't enter again
+    inside: BTreeSet<(CollisionObjectHandle, CollisionObjectHandle)>,
+    ///How many objects have been spawned, for numbering their generations
+    spawned: u64,
+    ///The most recent script error, shown on screen until another replaces it
+    last_error: Option<String>,
     color: [f32; 4],
-    world: CollisionWorld2<f64, ()>
+    world: CollisionWorld2<f64, Collider>,
+    ///The tileset named by the map's `font` property
+    font: Option<text::Font>,
+    ///What scripts drew with `draw_text` during the last tick
+    text: Vec<text::Text>,
+    ///From tilesets' `palette_cycle` properties and `cycle_palette` in scripts
+    palette_cycles: Vec<PaletteCycle>,
 }
 
 impl Map {
-    fn load<R: gfx::Resources, F: gfx::Factory<R>>(graphics: &mut Graphics<R, F>, tiles: &mut Tiles, filename: &str) -> Map {
-        let tiled_map = tiled::parse_file(std::path::Path::new(filename)).unwrap();
-        let mut map = HashMap::new();
+    ///Loads the map at `filename` along with its tilesets and objects, or explains why it couldn't be
+    fn load(tiles: &mut Tiles, filename: &str) -> Result<Map, String> {
+        Map::load_checked(tiles, filename, |_| Ok(()))
+    }
+
+    ///Like `load`, but has `check` look over the new tiles, such as by making their textures, before any script sees
+    ///the map. If either fails, `tiles` and the scripts are left as they were so the old map can keep running.
+    fn load_checked<F: FnOnce(&Tiles) -> Result<(), String>>(tiles: &mut Tiles, filename: &str, check: F) -> Result<Map, String> {
+        let saved = tiles.clone();
+        let result = Map::build(tiles, filename, check);
+        if result.is_err() {
+            *tiles = saved;
+        }
+        result
+    }
+
+    fn build<F: FnOnce(&Tiles) -> Result<(), String>>(tiles: &mut Tiles, filename: &str, check: F) -> Result<Map, String> {
+        let tiled_map = tiled::parse_file(std::path::Path::new(filename)).map_err(|error| format!("Failed to load {}: {:?}", filename, error))?;
+        let mut layers = Vec::new();
         println!("{:#?}", tiled_map);
         let mut tile_lookup = HashMap::new();
+        let font_name = match tiled_map.properties.get("font") {
+            Some(&tiled::PropertyValue::StringValue(ref name)) => Some(name.clone()),
+            _ => None
+        };
+        let mut font = None;
+        let mut palette_cycles = Vec::new();
         for tileset in tiled_map.tilesets {
-            tile_lookup.extend(tiles.load(graphics, tileset))
+            let palette = match tileset.properties.get("palette") {
+                Some(&tiled::PropertyValue::FileValue(ref palette)) | Some(&tiled::PropertyValue::StringValue(ref palette)) => Some(palette.clone()),
+                _ => None
+            };
+            if let (Some(ref palette), Some(&tiled::PropertyValue::StringValue(ref cycle))) = (palette, tileset.properties.get("palette_cycle")) {
+                //Tiles::load resolves palette paths and transparent indexes the same way
+                let palette = format!("assets/tiled/{}", palette);
+                let transparent = match tileset.properties.get("transparent_index") {
+                    Some(&tiled::PropertyValue::IntValue(v)) if v >= 0 => Some(v as usize),
+                    _ => None
+                };
+                for cycle in PaletteCycle::parse(&palette, palette_size(&palette, transparent)?, cycle)? {
+                    cycle.insert_into(&mut palette_cycles);
+                }
+            }
+            if font_name.as_ref() == Some(&tileset.name) {
+                let name = tileset.name.clone();
+                let font_tileset = tileset.clone();
+                let mapping = tiles.load(tileset)?;
+                font = Some(text::Font::new(&font_tileset, tiles.offsets[&name].0, mapping.len()));
+                tile_lookup.extend(mapping);
+            } else {
+                tile_lookup.extend(tiles.load(tileset)?)
+            }
+        }
+        if font.is_none() {
+            if let Some(ref name) = font_name {
+                println!("No tileset called {} for the font", name);
+            }
         }
         
         let mut world = CollisionWorld2::new(0.02);
         let mut map_groups = CollisionGroups::new();
         map_groups.set_membership(&[1]);
         map_groups.set_whitelist(&[2]);
-        let mut object_groups = CollisionGroups::new();
-        object_groups.set_membership(&[2]);
         let contacts_query = GeometricQueryType::Contacts(0.0, 0.0);
-        world.add(Isometry2::new(Vector2::new(0.0, 0.0), nalgebra::zero()), ShapeHandle2::new(Plane2::new(Vector2::x_axis())), map_groups, contacts_query, ());
-        world.add(Isometry2::new(Vector2::new(tiled_map.width as f64 * tiled_map.tile_width as f64, 0.0), nalgebra::zero()), ShapeHandle2::new(Plane2::new(-Vector2::x_axis())), map_groups, contacts_query, ());
-        world.add(Isometry2::new(Vector2::new(0.0, 0.0), nalgebra::zero()), ShapeHandle2::new(Plane2::new(Vector2::y_axis())), map_groups, contacts_query, ());
-        world.add(Isometry2::new(Vector2::new(0.0, tiled_map.height as f64 * tiled_map.tile_height as f64), nalgebra::zero()), ShapeHandle2::new(Plane2::new(-Vector2::y_axis())), map_groups, contacts_query, ());
+        world.add(Isometry2::new(Vector2::new(0.0, 0.0), nalgebra::zero()), ShapeHandle2::new(Plane2::new(Vector2::x_axis())), map_groups, contacts_query, Collider::Map);
+        world.add(Isometry2::new(Vector2::new(tiled_map.width as f64 * tiled_map.tile_width as f64, 0.0), nalgebra::zero()), ShapeHandle2::new(Plane2::new(-Vector2::x_axis())), map_groups, contacts_query, Collider::Map);
+        world.add(Isometry2::new(Vector2::new(0.0, 0.0), nalgebra::zero()), ShapeHandle2::new(Plane2::new(Vector2::y_axis())), map_groups, contacts_query, Collider::Map);
+        world.add(Isometry2::new(Vector2::new(0.0, tiled_map.height as f64 * tiled_map.tile_height as f64), nalgebra::zero()), ShapeHandle2::new(Plane2::new(-Vector2::y_axis())), map_groups, contacts_query, Collider::Map);
         
         for layer in tiled_map.layers {
+            let (solid, foreground, palette_row) = {
+                let flag = |name: &str, default: bool| layer.properties.get(name).map_or(default, |prop| match prop {
+                    tiled::PropertyValue::BoolValue(v) => *v,
+                    _ => default
+                });
+                //Otherwise each tile uses the palette row it was matched against
+                let palette_row = layer.properties.get("palette_row").map_or(None, |prop| match prop {
+                    tiled::PropertyValue::IntValue(v) if *v >= 0 => Some(*v as usize),
+                    _ => None
+                });
+                (flag("solid", true), flag("foreground", false), palette_row)
+            };
+            let mut map = HashMap::new();
             for (y, row) in layer.tiles.into_iter().enumerate() {
                 for (x, tile) in row.into_iter().enumerate() {
                     let (rotation, flipped) = match tile >> 29 {
@@ -433,40 +987,28 @@ impl Map {
                         _ => unreachable!()
                     };
                     if let Some(&tile) = tile_lookup.get(&(tile & 0x0fffffff)) {
-                        let handle = world.add(Isometry2::new(Vector2::new(x as f64 * tiled_map.tile_width as f64, y as f64 * tiled_map.tile_height as f64), nalgebra::zero()), tiles.get(tile).hitbox.clone(), map_groups, contacts_query, ());
-                        print!("{:?}: {}:({},{},{}), ", (x as f64 * tiled_map.tile_width as f64, y as f64 * tiled_map.tile_height as f64), tile, rotation, flipped, handle.0);
+                        if solid {
+                            let handle = world.add(Isometry2::new(Vector2::new(x as f64 * tiled_map.tile_width as f64, y as f64 * tiled_map.tile_height as f64), nalgebra::zero()), tiles.get(tile).hitbox(rotation, flipped), map_groups, contacts_query, Collider::Map);
+                            print!("{:?}: {}:({},{},{}), ", (x as f64 * tiled_map.tile_width as f64, y as f64 * tiled_map.tile_height as f64), tile, rotation, flipped, handle.0);
+                        }
                         map.insert((x as i32, y as i32), MapTile {
                             tile,
                             rotation,
-                            flipped
+                            flipped,
+                            palette: palette_row.unwrap_or(tiles.get(tile).palette_id),
                         });
                     }
                 }
                 println!();
             }
+            layers.push(Layer {
+                name: layer.name,
+                visible: layer.visible,
+                solid,
+                foreground,
+                tiles: map,
+            });
         }
-        let mut objects = HashMap::new();
-        for group in tiled_map.object_groups {
-            for object in group.objects {
-                let tile_id = tile_lookup[&(object.gid & 0x0fffffff)];
-                let tile = tiles.get(tile_id);
-                let rotation = (((object.rotation / 90.0).round() as i32 % 4) + 4) as usize % 4;
-                let (x, y) = match rotation {
-                    0 => (object.x, object.y),
-                    1 => (object.x, object.y + tile.height as f32),
-                    2 => (object.x - tile.width as f32, object.y + tile.height as f32),
-                    3 => (object.x - tile.width as f32, object.y),
-                    _ => unreachable!()
-                };
-                let flipped = (object.gid >> 31) == 1;
-                let x = x as f64;
-                let y = y as f64 - tile.height as f64;
-                let handle = world.add(Isometry2::new(Vector2::new(x, y), nalgebra::zero()), tile.hitbox.clone(), object_groups, contacts_query, ());
-                let mut object = Object::new(tiles.lua, tile, x, y, rotation, flipped);
-                objects.insert(handle, (tile_id, object));
-            }
-        }
-        
         let color = if let Some(color) = tiled_map.background_colour {
             let color = palette::Srgb::from_pixel(&[color.red, color.green, color.blue]).into_linear();
             [
@@ -478,115 +1020,969 @@ impl Map {
         } else {
             [0.0, 0.0, 0.0, 1.0]
         };
-        Map {
-            map,
-            objects,
+        let tick_rate = tiled_map.properties.get("tick_rate").map_or(None, |prop| match prop {
+            tiled::PropertyValue::IntValue(v) => Some(*v as f64),
+            tiled::PropertyValue::FloatValue(v) => Some(*v as f64),
+            _ => None
+        }).unwrap_or(120.0);
+        if tick_rate.is_nan() || tick_rate <= 0.0 {
+            return Err(format!("{} has a tick_rate of {}, but it needs to be above 0", filename, tick_rate))
+        }
+        let info = MapInfo {
+            columns: tiled_map.width,
+            rows: tiled_map.height,
+            tile_width: tiled_map.tile_width,
+            tile_height: tiled_map.tile_height,
+            background: tiled_map.background_colour.map_or([0, 0, 0], |color| [color.red, color.green, color.blue]),
+            properties: tiled_map.properties.clone(),
+            shapes: world.collision_objects()
+                .filter(|collision_object| *collision_object.data() == Collider::Map)
+                .map(|collision_object| (collision_object.position().clone(), collision_object.shape().clone()))
+                .collect(),
+        };
+        for object in tiled_map.object_groups.iter().flat_map(|group| group.objects.iter()) {
+            let gid = object.gid & 0x0fffffff;
+            if object.gid != 0 && !tile_lookup.contains_key(&gid) {
+                return Err(format!("Object {} in {} uses tile {}, which none of the map's tilesets have", object.id, filename, gid))
+            }
+        }
+        check(tiles)?;
+        //Scripts see the map from here on, so nothing after this can fail
+        let lua = tiles.lua;
+        lua.set_named_registry_value("spawns", lua.create_table().unwrap()).unwrap();
+        lua.set_named_registry_value("destroyed", lua.create_table().unwrap()).unwrap();
+        text::set_globals(lua).expect("Unable to set draw_text");
+        lua.set_named_registry_value("palette_cycles", lua.create_table().unwrap()).unwrap();
+        //The palette and its size for every tile name, so cycle_palette can check its range when it's called
+        let mut sizes = HashMap::new();
+        let mut palettes = HashMap::new();
+        for (name, &tile_id) in &tiles.names {
+            let tile = tiles.get(tile_id);
+            let size = sizes.entry((tile.palette.clone(), tile.transparent))
+                .or_insert_with(|| palette_size(&tile.palette, tile.transparent).ok());
+            if let Some(size) = *size {
+                palettes.insert(name.clone(), (tile.palette.clone(), size));
+            }
+        }
+        //Cycles the palette used by the named tile, replacing any cycle over the same range
+        let cycle_palette = lua.create_function(move |lua, (tile, start, end, speed): (String, usize, usize, f64)| {
+            let &(ref palette, size) = palettes.get(&tile)
+                .ok_or_else(|| rlua::Error::RuntimeError(format!("Can't cycle the palette of unknown tile {}", tile)))?;
+            let cycle = PaletteCycle { palette: palette.clone(), start, end, speed };
+            cycle.check(size).map_err(rlua::Error::RuntimeError)?;
+            let cycles: rlua::Table = lua.named_registry_value("palette_cycles")?;
+            let cycle = lua.create_table()?;
+            cycle.set("palette", palette.as_str())?;
+            cycle.set("start", start)?;
+            cycle.set("end", end)?;
+            cycle.set("speed", speed)?;
+            cycles.set(cycles.len()? + 1, cycle)
+        }).unwrap();
+        lua.globals().set("cycle_palette", cycle_palette).unwrap();
+        let mut map = Map {
+            layers: Arc::new(layers),
+            info: Arc::new(info),
+            tile_lookup,
+            tick_rate,
+            scaling: Scaling::from_properties(&tiled_map.properties),
+            objects: BTreeMap::new(),
+            combat: BTreeMap::new(),
+            hits: BTreeSet::new(),
+            triggers: BTreeMap::new(),
+            inside: BTreeSet::new(),
+            spawned: 0,
+            last_error: None,
             color,
             world,
+            font,
+            text: Vec::new(),
+            palette_cycles,
+        };
+        lua.globals().set("world", map.world()).expect("Unable to set world");
+        lua.globals().set("camera", camera::Camera::new(&map.info)).expect("Unable to set camera");
+        for group in tiled_map.object_groups {
+            for object in group.objects {
+                if object.gid == 0 {
+                    map.add_trigger(lua, &object);
+                    continue
+                }
+                //Every object's tile was checked for before any script ran
+                let tile_id = map.tile_lookup[&(object.gid & 0x0fffffff)];
+                let (x, y, rotation, flipped) = {
+                    let tile = tiles.get(tile_id);
+                    let rotation = (((object.rotation / 90.0).round() as i32 % 4) + 4) as usize % 4;
+                    let (x, y) = match rotation {
+                        0 => (object.x, object.y),
+                        1 => (object.x, object.y + tile.height as f32),
+                        2 => (object.x - tile.width as f32, object.y + tile.height as f32),
+                        3 => (object.x - tile.width as f32, object.y),
+                        _ => unreachable!()
+                    };
+                    let flipped = (object.gid >> 31) == 1;
+                    (x as f64, y as f64 - tile.height as f64, rotation, flipped)
+                };
+                //Lets designers set per-instance values in Tiled, read by the script as object.name, object.type and so on
+                let properties = properties_table(lua, &object.properties).unwrap();
+                if !object.name.is_empty() {
+                    properties.set("name", object.name.as_str()).unwrap();
+                }
+                if !object.obj_type.is_empty() {
+                    properties.set("type", object.obj_type.as_str()).unwrap();
+                }
+                map.spawn(lua, tiles, tile_id, x, y, rotation, flipped, Some(properties));
+            }
         }
+        map.update_combat(tiles, 0.0);
+        Ok(map)
     }
-}
-
-struct Tiles<'a> {
-    tiles: Vec<Tile<'a>>,
-    offsets: HashMap<String, usize>,
-    lua: &'a Lua,
-}
-
-impl<'a> Tiles<'a> {
-    fn new(lua: &'a Lua) -> Tiles<'a> {
-        Tiles {
-            tiles: Vec::new(),
-            offsets: HashMap::new(),
-            lua,
-        }
-    }
-    fn load<R: gfx::Resources, F: gfx::Factory<R>>(&mut self, graphics: &mut Graphics<R, F>, tileset: Tileset) -> HashMap<u32, usize> {
-        use std::fs::File;
-        use tiled::PropertyValue::*;
-        let first_gid = tileset.first_gid;
-        if let Some(offset) = self.offsets.get(&tileset.name) {
-            return tileset.tiles.into_iter().enumerate().map(|(i, tile)| (tile.id + first_gid, offset + i)).collect()
-        }
-        self.offsets.insert(tileset.name, self.tiles.len());
-        let default_palette = tileset.properties.get("palette").map_or(None, |prop| match prop {
-            StringValue(v) => Some(v),
-            FileValue(v) => Some(v),
-            _ => None
-        }).expect("Tileset has no palette");
 
-        let tiles = &mut self.tiles;
-        let lua = self.lua;
-        let offset = tiles.len();
-        let mut mappings = HashMap::new();
-        //Can't correctly set the animation frames until the real indexes are known
-        let mut animations = Vec::new();
-        for (i, tile) in tileset.tiles.into_iter().enumerate() {
-            let palette = tile.properties.get("palette").map_or(None, |prop| match prop {
-                StringValue(v) => Some(v),
-                FileValue(v) => Some(v),
-                _ => None
-            }).unwrap_or(default_palette);
-            let palette_id = tile.properties.get("palette_id").map_or(0, |prop| match prop {
-                IntValue(v) => *v as usize,
-                ColorValue(v) => *v as usize,
-                FloatValue(v) => *v as usize,
-                StringValue(v) => v.parse().unwrap_or(0),
-                _ => 0
-            });
-            let script = tile.properties.get("script").map_or(None, |prop| match prop {
-                StringValue(v) | FileValue(v) => {
-                    let path = format!("assets/tiled/{}", v);
-                    let mut file = File::open(&path).expect("Couldn't find script");
-                    let mut contents = String::new();
-                    file.read_to_string(&mut contents).expect("Failed to read file");
-                    Some(lua.load(&contents, Some(&path)).expect("Failed to load script"))
-                }
-                _ => None
-            });
-            mappings.insert(first_gid + tile.id, offset + i);
-            if let Some(animation) = tile.animation {
-                animations.push((offset + i, animation));
+    ///Adds an object to the collision world, copies `properties` into its table and runs its script's `init`
+    fn spawn(&mut self, lua: &Lua, tiles: &Tiles, tile_id: usize, x: f64, y: f64, rotation: usize, flipped: bool, properties: Option<rlua::Table>) -> CollisionObjectHandle {
+        let mut object_groups = CollisionGroups::new();
+        object_groups.set_membership(&[2]);
+        object_groups.set_whitelist(&[1, 2, 5]);
+        let tile = tiles.get(tile_id);
+        //Ticks swap in the shape for the current animation frame from then on
+        let shape = tiles.hitbox_tile(tile_id, 0.0);
+        let handle = self.world.add(Isometry2::new(Vector2::new(x, y), nalgebra::zero()), tiles.get(shape).hitbox(rotation, flipped), object_groups, GeometricQueryType::Contacts(0.0, 0.0), Collider::Object);
+        self.spawned += 1;
+        let mut object = Object::new(lua, tile, handle, self.spawned, x, y, rotation, flipped, shape);
+        if let Some(properties) = properties {
+            if let Ok(Some(palette)) = properties.get::<_, Option<usize>>("palette_row") {
+                object.palette = palette;
+            }
+            let table: rlua::Table = lua.registry_value(&object.key).unwrap();
+            for pair in properties.pairs::<rlua::Value, rlua::Value>() {
+                let (key, value) = pair.expect("Invalid spawn properties");
+                table.set(key, value).expect("Failed to set registry value");
             }
-            let shape = ShapeHandle2::new(Cuboid2::new(Vector2::new(tile.images[0].width as f64 / 2.0, tile.images[0].height as f64 / 2.0)));
-            tiles.push(Tile {
-                texture: graphics.load_texture(&tile.images[0].source, &format!("assets/tiled/{}", palette), palette_id),
-                animation: Vec::new(),
-                width: tile.images[0].width as u32,
-                height: tile.images[0].height as u32,
-                script,
-                hitbox: shape,
-            });
         }
-        for (tile, animation) in animations {
-            tiles[tile].animation = animation.into_iter().map(|frame| Frame {tile: mappings[&(frame.tile_id + first_gid)], duration: frame.duration }).collect();
+        if let Some(error) = object.init(lua, handle, tile) {
+            self.last_error = Some(error);
         }
-        mappings
+        self.objects.insert(handle, (tile_id, object));
+        handle
     }
 
-    fn get(&self, index: usize) -> &Tile {
-        &self.tiles[index]
-    }
-}
+    ///Adds a Tiled object without a tile as a region that calls `on_enter` and `on_exit` when objects cross it
+    fn add_trigger(&mut self, lua: &Lua, object: &tiled::Object) {
+        let shape = match HitShape::from_object(object) {
+            //Points come through as empty rectangles, which need some size to be found
+            Some(HitShape::Rect(x, y, width, height)) => HitShape::Rect(x, y, width.max(1.0), height.max(1.0)),
+            Some(shape) => shape,
+            None => return
+        };
+        let mut trigger_groups = CollisionGroups::new();
+        trigger_groups.set_membership(&[5]);
+        trigger_groups.set_whitelist(&[2]);
+        //Tiled turns objects clockwise about their x and y, which is the positive direction with y pointing down
+        let origin = Vector2::new(object.x as f64, object.y as f64);
+        let position = Isometry2::new(origin, (object.rotation as f64).to_radians()) * Isometry2::new(-origin, 0.0);
+        let handle = self.world.add(position, HitShape::compound(&[shape], 0.0, 0.0, 0, false), trigger_groups, GeometricQueryType::Proximity(0.0), Collider::Trigger);
 
-struct Tile<'a> {
-    texture: usize,
-    width: u32,
-    height: u32,
-    animation: Vec<Frame>,
-    script: Option<rlua::Function<'a>>,
-    hitbox: ShapeHandle2<f64>,
-}
+        let table = properties_table(lua, &object.properties).unwrap();
+        table.set("id", object.id).unwrap();
+        table.set("name", object.name.as_str()).unwrap();
+        table.set("type", object.obj_type.as_str()).unwrap();
+        table.set("x", object.x).unwrap();
+        table.set("y", object.y).unwrap();
+        table.set("width", object.width).unwrap();
+        table.set("height", object.height).unwrap();
+        let path = object.properties.get("script").map_or(None, |prop| match prop {
+            tiled::PropertyValue::StringValue(v) | tiled::PropertyValue::FileValue(v) => Some(format!("assets/tiled/{}", v)),
+            _ => None
+        });
+        let script = path.clone().map(Script::read);
+        let env = match script {
+            Some(Err(ref error)) => {
+                println!("Script error, disabling trigger: {}", error);
+                self.last_error = Some(error.clone());
+                None
+            }
+            Some(Ok(ref script)) => match script.load(lua) {
+                Ok(env) => Some(env),
+                Err(error) => {
+                    let message = format!("load failed for trigger {} ({}): {}", object.id, script.path, error);
+                    println!("Script error, disabling trigger: {}", message);
+                    self.last_error = Some(message);
+                    None
+                }
+            },
+            None => None
+        };
+        self.triggers.insert(handle, Trigger {
+            path,
+            table: lua.create_registry_value(table).unwrap(),
+            env,
+        });
+    }
+
+    ///Calls `on_enter` or `on_exit` on the object and the trigger for every object that crossed a trigger this tick,
+    ///in (trigger, object) handle order rather than ncollide's hash order, so replays match their recordings
+    fn trigger(&mut self, lua: &Lua, tiles: &Tiles) {
+        //Compared with the last update's overlaps like hits are, since a shape swap drops its pairs without an exit event
+        let mut inside = BTreeSet::new();
+        for (first, second, detector) in self.world.proximity_pairs() {
+            if detector.proximity() != Proximity::Intersecting {
+                continue
+            }
+            match (*first.data(), *second.data()) {
+                (Collider::Trigger, Collider::Object) => {inside.insert((first.handle(), second.handle()));}
+                (Collider::Object, Collider::Trigger) => {inside.insert((second.handle(), first.handle()));}
+                _ => ()
+            }
+        }
+        let events: Vec<_> = inside.symmetric_difference(&self.inside).map(|&(trigger, object)| (trigger, object, inside.contains(&(trigger, object)))).collect();
+        self.inside = inside;
+        for (trigger_handle, object_handle, entered) in events {
+            let function = if entered {"on_enter"} else {"on_exit"};
+            let trigger = self.triggers.get_mut(&trigger_handle).unwrap();
+            let table: rlua::Table = lua.registry_value(&trigger.table).unwrap();
+            let object = match self.objects.get_mut(&object_handle) {
+                Some(&mut (tile_id, ref mut object)) => {
+                    if let Some(error) = object.call_logged(lua, object_handle, tiles.get(tile_id), function, table.clone()) {
+                        self.last_error = Some(error);
+                    }
+                    Object { view: true, ..object.clone() }
+                }
+                None => continue
+            };
+            let result = match trigger.env {
+                Some(ref env) => {
+                    let env: rlua::Table = lua.registry_value(env).unwrap();
+                    match env.get::<_, Option<rlua::Function>>(function) {
+                        Ok(Some(callback)) => {
+                            env.set("trigger", table).unwrap();
+                            callback.call::<_, ()>(object)
+                        }
+                        Ok(None) => Ok(()),
+                        Err(error) => Err(error)
+                    }
+                }
+                None => Ok(())
+            };
+            if let Err(error) = result {
+                let message = format!("{} failed for trigger ({}): {}", function, trigger.path.as_ref().map_or("<no script>", |path| path.as_str()), error);
+                println!("Script error, disabling trigger: {}", message);
+                self.last_error = Some(message);
+                trigger.env = None;
+            }
+        }
+    }
+
+    fn remove(&mut self, handle: CollisionObjectHandle) {
+        if self.objects.remove(&handle).is_some() {
+            self.world.remove(&[handle]);
+        }
+        //The handle can be reused by the next spawn, which shouldn't inherit this object's overlaps
+        self.hits = self.hits.iter().cloned().filter(|&(attacker, victim)| attacker != handle && victim != handle).collect();
+        self.inside = self.inside.iter().cloned().filter(|&(_, object)| object != handle).collect();
+        if let Some(combat) = self.combat.remove(&handle) {
+            self.world.remove(&[combat.hitbox]);
+            if let Some(hurtbox) = combat.hurtbox {
+                self.world.remove(&[hurtbox]);
+            }
+        }
+    }
+
+    ///Removes the objects scripts destroyed, adds the ones they spawned and starts the palette cycles they asked for since the last call
+    fn apply_script_changes(&mut self, lua: &Lua, tiles: &Tiles) -> rlua::Result<()> {
+        use rlua::Value;
+        let destroyed: rlua::Table = lua.named_registry_value("destroyed")?;
+        lua.set_named_registry_value("destroyed", lua.create_table()?)?;
+        //Removed in handle order, since that decides which slots later spawns reuse
+        let mut handles = Vec::new();
+        for pair in destroyed.pairs::<usize, u64>() {
+            let (handle, generation) = pair?;
+            let handle = CollisionObjectHandle(handle);
+            if self.objects.get(&handle).map(|&(_, ref object)| object.generation) == Some(generation) {
+                handles.push(handle);
+            }
+        }
+        handles.sort();
+        for handle in handles {
+            self.remove(handle);
+        }
+        let spawns: rlua::Table = lua.named_registry_value("spawns")?;
+        lua.set_named_registry_value("spawns", lua.create_table()?)?;
+        for spawn in spawns.sequence_values::<rlua::Table>() {
+            let spawn = spawn?;
+            let tile = spawn.get::<_, Value>("tile")?;
+            let tile_id = match tile {
+                Value::Integer(gid) => self.tile_lookup.get(&(gid as u32)).cloned(),
+                Value::Number(gid) => self.tile_lookup.get(&(gid as u32)).cloned(),
+                Value::String(ref name) => tiles.names.get(name.to_str()?).cloned(),
+                _ => None
+            };
+            match tile_id {
+                Some(tile_id) => {
+                    self.spawn(lua, tiles, tile_id, spawn.get("x")?, spawn.get("y")?, 0, false, spawn.get("properties")?);
+                }
+                None => println!("Can't spawn unknown tile {:?}", tile)
+            }
+        }
+        let cycles: rlua::Table = lua.named_registry_value("palette_cycles")?;
+        lua.set_named_registry_value("palette_cycles", lua.create_table()?)?;
+        for cycle in cycles.sequence_values::<rlua::Table>() {
+            let cycle = cycle?;
+            let cycle = PaletteCycle {
+                palette: cycle.get("palette")?,
+                start: cycle.get("start")?,
+                end: cycle.get("end")?,
+                speed: cycle.get("speed")?,
+            };
+            cycle.insert_into(&mut self.palette_cycles);
+        }
+        Ok(())
+    }
+
+    ///Runs every object's script and moves it, then handles any hits. `delta` is always one tick long.
+    fn tick(&mut self, lua: &Lua, tiles: &Tiles, counter: f64) {
+        lua.globals().set("world", self.world()).expect("Unable to set world");
+        for (handle, &mut (tile_id, ref mut object)) in &mut self.objects {
+            object.last_x = object.x;
+            object.last_y = object.y;
+            if let Some(error) = object.call_logged(lua, *handle, tiles.get(tile_id), "update", ()) {
+                self.last_error = Some(error);
+            }
+            if !object.frozen {
+                object.update_shape(&mut self.world, *handle, tiles, tile_id, counter);
+                object.slide(&mut self.world, *handle);
+            }
+        }
+        if let Err(error) = self.apply_script_changes(lua, tiles) {
+            println!("Failed to spawn or destroy objects: {}", error);
+            self.last_error = Some(error.to_string());
+        }
+        self.update_combat(tiles, counter);
+        for (attacker, victim, damage) in self.combat_events(tiles) {
+            let (attacker_tile, mut attacker_object) = self.objects[&attacker].clone();
+            let (victim_tile, mut victim_object) = self.objects[&victim].clone();
+            if let Some(error) = attacker_object.call_logged(lua, attacker, tiles.get(attacker_tile), "on_hit", (Object { view: true, ..victim_object.clone() }, damage, true)) {
+                self.last_error = Some(error);
+            }
+            if let Some(error) = victim_object.call_logged(lua, victim, tiles.get(victim_tile), "on_hit", (Object { view: true, ..attacker_object.clone() }, damage, false)) {
+                self.last_error = Some(error);
+            }
+            self.objects.get_mut(&attacker).unwrap().1 = attacker_object;
+            self.objects.get_mut(&victim).unwrap().1 = victim_object;
+        }
+        self.trigger(lua, tiles);
+        self.update_camera(lua);
+        match text::take(lua) {
+            Ok(text) => self.text = text,
+            Err(error) => {
+                println!("Failed to draw text: {}", error);
+                self.last_error = Some(error.to_string());
+            }
+        }
+    }
+
+    ///Moves the camera after everything else this tick, so it follows where its target ended up
+    fn update_camera(&self, lua: &Lua) {
+        let globals = lua.globals();
+        let camera = match globals.get::<_, rlua::AnyUserData>("camera") {
+            Ok(camera) => camera,
+            Err(_) => return
+        };
+        let delta: f64 = globals.get("delta").expect("Unable to get delta");
+        let mut camera = match camera.borrow_mut::<camera::Camera>() {
+            Ok(camera) => camera,
+            Err(_) => return
+        };
+        camera.update(&self.objects, delta);
+    }
+
+    ///Gives every object and trigger using the script at `path` a fresh environment from it, keeping objects' state
+    ///tables, and unfreezes them
+    fn reload_script(&mut self, lua: &Lua, tiles: &Tiles, path: &str) {
+        self.last_error = None;
+        for (handle, &mut (tile_id, ref mut object)) in &mut self.objects {
+            let tile = tiles.get(tile_id);
+            let script = match tile.script {
+                Some(ref script) if script.path == path => script,
+                _ => continue
+            };
+            object.frozen = false;
+            match script.load(lua) {
+                Ok(env) => object.env = Some(Arc::new(env)),
+                Err(error) => self.last_error = Some(object.fail(*handle, tile, "reload", error))
+            }
+        }
+        for trigger in self.triggers.values_mut() {
+            if trigger.path.as_ref().map(|script| script.as_str()) != Some(path) {
+                continue
+            }
+            let result = Script::read(path.to_string()).and_then(|script| script.load(lua).map_err(|error| error.to_string()));
+            match result {
+                Ok(env) => trigger.env = Some(env),
+                Err(error) => {
+                    let message = format!("reload failed for trigger ({}): {}", path, error);
+                    println!("Script error, disabling trigger: {}", message);
+                    self.last_error = Some(message);
+                    trigger.env = None;
+                }
+            }
+        }
+    }
+
+    ///A snapshot of the map for scripts
+    fn world(&self) -> World {
+        World {
+            map: self.info.clone(),
+            layers: self.layers.clone(),
+            objects: self.objects.iter()
+                .map(|(&handle, &(_, ref object))| {
+                    let view = Object { view: true, ..object.clone() };
+                    (view, self.world.collision_object(handle).unwrap().shape().clone())
+                })
+                .collect(),
+        }
+    }
+
+    ///A hash of every object's position, rotation and flip, for noticing when a replay stops matching its recording
+    fn state_hash(&self) -> u64 {
+        use std::hash::{Hash, Hasher};
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        for (handle, &(tile, ref object)) in &self.objects {
+            (handle.0, tile, object.x.to_bits(), object.y.to_bits(), object.rotation, object.flipped).hash(&mut hasher);
+        }
+        hasher.finish()
+    }
+
+    ///Moves every object's hitbox and hurtbox to match its position and current animation frame
+    fn update_combat(&mut self, tiles: &Tiles, counter: f64) {
+        let mut hitbox_groups = CollisionGroups::new();
+        hitbox_groups.set_membership(&[4]);
+        hitbox_groups.set_whitelist(&[3]);
+        let mut hurtbox_groups = CollisionGroups::new();
+        hurtbox_groups.set_membership(&[3]);
+        hurtbox_groups.set_whitelist(&[4]);
+        let proximity_query = GeometricQueryType::Proximity(0.0);
+        let world = &mut self.world;
+        for (&handle, &(tile, ref object)) in &self.objects {
+            let frame = (tiles.frame(tile, counter), object.rotation, object.flipped);
+            let hitbox_tile = tiles.hitbox_tile(tile, counter);
+            let position = Isometry2::new(Vector2::new(object.x, object.y), nalgebra::zero());
+            match self.combat.get(&handle) {
+                Some(combat) if combat.frame == frame && combat.hitbox_tile == hitbox_tile => {
+                    world.set_position(combat.hitbox, position);
+                    if let Some(hurtbox) = combat.hurtbox {
+                        world.set_position(hurtbox, position);
+                    }
+                    continue
+                }
+                Some(combat) => {
+                    world.remove(&[combat.hitbox]);
+                    if let Some(hurtbox) = combat.hurtbox {
+                        world.remove(&[hurtbox]);
+                    }
+                }
+                None => ()
+            }
+            let hitbox = world.add(position, tiles.get(hitbox_tile).hitbox(frame.1, frame.2), hitbox_groups, proximity_query, Collider::Hitbox(handle));
+            let hurtbox = tiles.get(frame.0).hurtbox(frame.1, frame.2).map(|shape| world.add(position, shape, hurtbox_groups, proximity_query, Collider::Hurtbox(handle)));
+            self.combat.insert(handle, Combat { frame, hitbox_tile, hitbox, hurtbox });
+        }
+        world.update();
+    }
+
+    ///Hurtboxes that started overlapping another object's hitbox since the last update, as (attacker, victim, damage)
+    ///in handle order, since ncollide keeps its pairs in a randomly seeded hash map and replays need the same order.
+    ///Overlaps are compared with the last update's rather than read from proximity events, because swapping a shape
+    ///for a new frame drops its proximity pairs without an event and would otherwise hit again every frame.
+    fn combat_events(&mut self, tiles: &Tiles) -> Vec<(CollisionObjectHandle, CollisionObjectHandle, f64)> {
+        let mut hits = BTreeSet::new();
+        for (first, second, detector) in self.world.proximity_pairs() {
+            if detector.proximity() != Proximity::Intersecting {
+                continue
+            }
+            match (*first.data(), *second.data()) {
+                (Collider::Hurtbox(attacker), Collider::Hitbox(victim)) | (Collider::Hitbox(victim), Collider::Hurtbox(attacker)) if attacker != victim => {
+                    hits.insert((attacker, victim));
+                }
+                _ => ()
+            }
+        }
+        let events = hits.difference(&self.hits)
+            .map(|&(attacker, victim)| (attacker, victim, tiles.get(self.combat[&attacker].frame.0).damage))
+            .collect();
+        self.hits = hits;
+        events
+    }
+}
+
+#[derive(Clone)]
+pub struct Tiles<'a> {
+    tiles: Vec<Tile>,
+    ///Tile indexes by their `name` property or image file name without the extension, for spawning from scripts
+    names: HashMap<String, usize>,
+    ///Where each tileset's tiles start and how many there are, by tileset name
+    offsets: HashMap<String, (usize, usize)>,
+    ///Tilesets to read again the next time a map uses them, replacing their tiles where they are
+    stale: HashSet<String>,
+    lua: &'a Lua,
+}
+
+impl<'a> Tiles<'a> {
+    fn new(lua: &'a Lua) -> Tiles<'a> {
+        Tiles {
+            tiles: Vec::new(),
+            names: HashMap::new(),
+            offsets: HashMap::new(),
+            stale: HashSet::new(),
+            lua,
+        }
+    }
+    fn load(&mut self, tileset: Tileset) -> Result<HashMap<u32, usize>, String> {
+        use tiled::PropertyValue::*;
+        let first_gid = tileset.first_gid;
+        //Spritesheet tilesets have one image cut into a grid, and only list the tiles with something extra like an animation
+        let sheet = tileset.images.get(0).map(|image| {
+            let columns = (image.width as u32 + tileset.spacing - 2 * tileset.margin) / (tileset.tile_width + tileset.spacing);
+            let rows = (image.height as u32 + tileset.spacing - 2 * tileset.margin) / (tileset.tile_height + tileset.spacing);
+            (image.source.clone(), columns, rows)
+        });
+        let ids: Vec<u32> = match sheet {
+            Some((_, columns, rows)) => (0..columns * rows).collect(),
+            None => tileset.tiles.iter().map(|tile| tile.id).collect()
+        };
+        let old_offset = self.offsets.get(&tileset.name).cloned();
+        if let Some((offset, _)) = old_offset {
+            if !self.stale.remove(&tileset.name) {
+                return Ok(ids.into_iter().enumerate().map(|(i, id)| (id + first_gid, offset + i)).collect())
+            }
+        }
+        //A tileset read again keeps its place unless its tile count changed, so tile indexes held elsewhere stay valid
+        let offset = match old_offset {
+            Some((offset, len)) if len == ids.len() => offset,
+            _ => self.tiles.len()
+        };
+        let default_palette = tileset.properties.get("palette").map_or(None, |prop| match prop {
+            StringValue(v) => Some(v),
+            FileValue(v) => Some(v),
+            _ => None
+        }).ok_or_else(|| format!("Tileset {} has no palette", tileset.name))?;
+        let transparent = tileset.properties.get("transparent_index").map_or(None, |prop| match prop {
+            IntValue(v) if *v >= 0 => Some(*v as usize),
+            _ => None
+        });
 
+        let mut tiles = Vec::new();
+        let mut mappings = HashMap::new();
+        //Can't correctly set the animation frames until the real indexes are known
+        let mut animations = Vec::new();
+        let no_properties = HashMap::new();
+        let mut described: HashMap<u32, tiled::Tile> = tileset.tiles.into_iter().map(|tile| (tile.id, tile)).collect();
+        for (i, id) in ids.into_iter().enumerate() {
+            let mut tile = described.remove(&id);
+            let (image, rect) = match sheet {
+                Some((ref source, columns, _)) => {
+                    let x = tileset.margin + (id % columns) * (tileset.tile_width + tileset.spacing);
+                    let y = tileset.margin + (id / columns) * (tileset.tile_height + tileset.spacing);
+                    (source.clone(), Some([x, y, tileset.tile_width, tileset.tile_height]))
+                }
+                None => {
+                    let image = &tile.as_ref().unwrap().images[0];
+                    (image.source.clone(), None)
+                }
+            };
+            let (width, height) = match (rect, tile.as_ref()) {
+                (Some(rect), _) => (rect[2], rect[3]),
+                (None, Some(tile)) => (tile.images[0].width as u32, tile.images[0].height as u32),
+                _ => unreachable!()
+            };
+            let animation = tile.as_mut().and_then(|tile| tile.animation.take());
+            let properties = tile.as_ref().map_or(&no_properties, |tile| &tile.properties);
+            let objectgroup = tile.as_ref().and_then(|tile| tile.objectgroup.as_ref());
+            let palette = properties.get("palette").map_or(None, |prop| match prop {
+                StringValue(v) => Some(v),
+                FileValue(v) => Some(v),
+                _ => None
+            }).unwrap_or(default_palette);
+            let palette_id = properties.get("palette_id").map_or(0, |prop| match prop {
+                IntValue(v) => *v as usize,
+                ColorValue(v) => *v as usize,
+                FloatValue(v) => *v as usize,
+                StringValue(v) => v.parse().unwrap_or(0),
+                _ => 0
+            });
+            let script = properties.get("script").map_or(None, |prop| match prop {
+                StringValue(v) | FileValue(v) => Some(format!("assets/tiled/{}", v)),
+                _ => None
+            });
+            let script = match script {
+                Some(path) => Some(Script::read(path)?),
+                None => None
+            };
+            mappings.insert(first_gid + id, offset + i);
+            if rect.is_some() {
+                self.names.insert(format!("{}:{}", tileset.name, id), offset + i);
+            } else if let Some(name) = std::path::Path::new(&image).file_stem() {
+                self.names.insert(name.to_string_lossy().into_owned(), offset + i);
+            }
+            if let Some(StringValue(name)) = properties.get("name") {
+                self.names.insert(name.clone(), offset + i);
+            }
+            if let Some(animation) = animation {
+                animations.push((offset + i, animation));
+            }
+            let mut shapes = HitShape::from_group(objectgroup, "Hitbox");
+            let (width, height) = (width as f64, height as f64);
+            let explicit_hitbox = !shapes.is_empty();
+            if shapes.is_empty() {
+                shapes.push(HitShape::Rect(0.0, 0.0, width, height));
+            }
+            let hitboxes = (0..8).map(|i| HitShape::compound(&shapes, width, height, i % 4, i >= 4)).collect();
+            let shapes = HitShape::from_group(objectgroup, "Hurtbox");
+            let hurtboxes = if shapes.is_empty() {
+                Vec::new()
+            } else {
+                (0..8).map(|i| HitShape::compound(&shapes, width, height, i % 4, i >= 4)).collect()
+            };
+            let damage = objectgroup.iter()
+                .flat_map(|group| group.objects.iter())
+                .filter(|object| object.obj_type == "Hurtbox")
+                .filter_map(|object| object.properties.get("damage"))
+                .chain(properties.get("damage"))
+                .next()
+                .map_or(1.0, |prop| match prop {
+                    IntValue(v) => *v as f64,
+                    FloatValue(v) => *v as f64,
+                    StringValue(v) => v.parse().unwrap_or(1.0),
+                    _ => 1.0
+                });
+            tiles.push(Tile {
+                image,
+                rect,
+                palette: format!("assets/tiled/{}", palette),
+                palette_id,
+                transparent,
+                animation: Vec::new(),
+                width: width as u32,
+                height: height as u32,
+                script,
+                explicit_hitbox,
+                hitboxes,
+                hurtboxes,
+                damage,
+            });
+        }
+        let name = &tileset.name;
+        for (tile, animation) in animations {
+            tiles[tile - offset].animation = animation.into_iter().map(|frame| match mappings.get(&(frame.tile_id + first_gid)) {
+                Some(&tile) => Ok(Frame {tile, duration: frame.duration }),
+                None => Err(format!("An animation in tileset {} uses tile {}, which the tileset doesn't have", name, frame.tile_id))
+            }).collect::<Result<_, _>>()?;
+        }
+        let end = offset + tiles.len();
+        self.offsets.insert(tileset.name.clone(), (offset, tiles.len()));
+        if offset == self.tiles.len() {
+            self.tiles.extend(tiles);
+        } else {
+            self.tiles.splice(offset..end, tiles);
+        }
+        Ok(mappings)
+    }
+
+    ///Marks every tileset to be read again, for when a tileset or map file changed
+    fn reload_tilesets(&mut self) {
+        self.stale = self.offsets.keys().cloned().collect();
+    }
+
+    fn get(&self, index: usize) -> &Tile {
+        &self.tiles[index]
+    }
+
+    ///Reads the script at `path` again for every tile using it
+    fn reload_script(&mut self, path: &str) {
+        use std::fs::File;
+        for tile in &mut self.tiles {
+            if let Some(ref mut script) = tile.script {
+                if script.path != path {
+                    continue
+                }
+                let mut source = String::new();
+                match File::open(path).and_then(|mut file| file.read_to_string(&mut source)) {
+                    Ok(_) => script.source = source,
+                    Err(error) => println!("Failed to reload {}: {}", path, error)
+                }
+            }
+        }
+    }
+
+    ///The tile whose hitbox `index` moves with at this point in time: its own if it has Hitbox objects, otherwise
+    ///the current animation frame's, or failing that the first frame with any
+    fn hitbox_tile(&self, index: usize, counter: f64) -> usize {
+        if self.tiles[index].explicit_hitbox {
+            return index
+        }
+        let frame = self.frame(index, counter);
+        if self.tiles[frame].explicit_hitbox {
+            return frame
+        }
+        self.tiles[index].animation.iter()
+            .map(|frame| frame.tile)
+            .find(|&frame| self.tiles[frame].explicit_hitbox)
+            .unwrap_or(index)
+    }
+
+    ///The tile to show for `index` at this point in time, following its animation if it has one
+    fn frame(&self, index: usize, counter: f64) -> usize {
+        let tile = &self.tiles[index];
+        if tile.animation.is_empty() {
+            index
+        } else {
+            let frame = (counter / (tile.animation[0].duration as f64 / 1000.0)) as usize % tile.animation.len();
+            tile.animation[frame].tile
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Tile {
+    ///Image path relative to assets/images, only turned into a texture by `Graphics::load_tiles`
+    image: String,
+    ///x, y, width and height of the tile within its image, for tiles cut from a spritesheet
+    rect: Option<[u32; 4]>,
+    palette: String,
+    palette_id: usize,
+    ///The palette index drawn as transparent, from the tileset's `transparent_index` property
+    transparent: Option<usize>,
+    width: u32,
+    height: u32,
+    animation: Vec<Frame>,
+    script: Option<Script>,
+    ///Whether `hitboxes` came from Hitbox objects rather than covering the whole image
+    explicit_hitbox: bool,
+    ///One compound shape for each rotation, then the same again flipped
+    hitboxes: Vec<ShapeHandle2<f64>>,
+    ///Where this tile hurts others, laid out like `hitboxes` or empty
+    hurtboxes: Vec<ShapeHandle2<f64>>,
+    damage: f64,
+}
+
+impl Tile {
+    fn hitbox(&self, rotation: usize, flipped: bool) -> ShapeHandle2<f64> {
+        self.hitboxes[rotation + if flipped {4} else {0}].clone()
+    }
+
+    fn hurtbox(&self, rotation: usize, flipped: bool) -> Option<ShapeHandle2<f64>> {
+        self.hurtboxes.get(rotation + if flipped {4} else {0}).cloned()
+    }
+}
+
+///A collision shape from a tile's object group, in image pixels
+enum HitShape {
+    ///x, y, width, height
+    Rect(f64, f64, f64, f64),
+    ///x, y, width, height of the bounding box
+    Ellipse(f64, f64, f64, f64),
+    Polygon(Vec<(f64, f64)>),
+}
+
+impl HitShape {
+    fn from_group(group: Option<&tiled::ObjectGroup>, obj_type: &str) -> Vec<HitShape> {
+        group.iter()
+            .flat_map(|group| group.objects.iter())
+            .filter(|object| object.obj_type == obj_type)
+            .filter_map(HitShape::from_object)
+            .collect()
+    }
+
+    fn from_object(object: &tiled::Object) -> Option<HitShape> {
+        use tiled::ObjectShape::*;
+        let x = object.x as f64;
+        let y = object.y as f64;
+        match object.shape {
+            Rect { width, height } => Some(HitShape::Rect(x, y, width as f64, height as f64)),
+            Ellipse { width, height } => Some(HitShape::Ellipse(x, y, width as f64, height as f64)),
+            Polygon { ref points } => Some(HitShape::Polygon(points.iter().map(|&(px, py)| (x + px as f64, y + py as f64)).collect())),
+            _ => {
+                println!("Unsupported hitbox shape: {:?}", object.shape);
+                None
+            }
+        }
+    }
+
+    ///Builds a compound shape for a tile drawn with the given rotation, matching the vertex buffers in `Sprite::new`
+    fn compound(shapes: &[HitShape], width: f64, height: f64, rotation: usize, flipped: bool) -> ShapeHandle2<f64> {
+        let transform = |x: f64, y: f64| {
+            let x = if flipped {width - x} else {x};
+            match rotation {
+                0 => (x, y),
+                1 => (height - y, x),
+                2 => (width - x, height - y),
+                _ => (y, width - x)
+            }
+        };
+        let mut parts = Vec::new();
+        for shape in shapes {
+            match *shape {
+                HitShape::Rect(x, y, w, h) => {
+                    let (x1, y1) = transform(x, y);
+                    let (x2, y2) = transform(x + w, y + h);
+                    let center = Vector2::new((x1 + x2) / 2.0, (y1 + y2) / 2.0);
+                    let half = Vector2::new((x2 - x1).abs() / 2.0, (y2 - y1).abs() / 2.0);
+                    parts.push((Isometry2::new(center, nalgebra::zero()), ShapeHandle2::new(Cuboid2::new(half))));
+                }
+                HitShape::Ellipse(x, y, w, h) => {
+                    let (cx, cy) = transform(x + w / 2.0, y + h / 2.0);
+                    let center = Vector2::new(cx, cy);
+                    if (w - h).abs() < 0.5 {
+                        parts.push((Isometry2::new(center, nalgebra::zero()), ShapeHandle2::new(Ball2::new(w / 2.0))));
+                    } else {
+                        //Not a circle, so approximate it with a polygon
+                        let points = (0..16).map(|i| {
+                            let angle = i as f64 * std::f64::consts::PI / 8.0;
+                            let (px, py) = transform(x + w / 2.0 * (1.0 + angle.cos()), y + h / 2.0 * (1.0 + angle.sin()));
+                            Point2::new(px - cx, py - cy)
+                        }).collect();
+                        parts.push((Isometry2::new(center, nalgebra::zero()), HitShape::convex(points)));
+                    }
+                }
+                HitShape::Polygon(ref points) => {
+                    let points = points.iter().map(|&(x, y)| {
+                        let (x, y) = transform(x, y);
+                        Point2::new(x, y)
+                    }).collect();
+                    for part in HitShape::convex_parts(points) {
+                        parts.push((Isometry2::identity(), HitShape::convex(part)));
+                    }
+                }
+            }
+        }
+        ShapeHandle2::new(Compound2::new(parts))
+    }
+
+    ///Polygons passed here have to be convex, but flipping reverses their winding
+    fn convex(mut points: Vec<Point2<f64>>) -> ShapeHandle2<f64> {
+        HitShape::wind(&mut points);
+        ShapeHandle2::new(ConvexHull2::new(points))
+    }
+
+    ///Puts the points in the winding order where convex corners turn positively
+    fn wind(points: &mut Vec<Point2<f64>>) {
+        let mut area = 0.0;
+        for i in 0..points.len() {
+            let a = points[i];
+            let b = points[(i + 1) % points.len()];
+            area += a.x * b.y - b.x * a.y;
+        }
+        if area < 0.0 {
+            points.reverse();
+        }
+    }
+
+    ///Splits a polygon into triangles by clipping off ears, unless it's convex already
+    fn convex_parts(mut points: Vec<Point2<f64>>) -> Vec<Vec<Point2<f64>>> {
+        //Positive for a left turn from a to b to c, once wound
+        fn turn(a: Point2<f64>, b: Point2<f64>, c: Point2<f64>) -> f64 {
+            (b.x - a.x) * (c.y - a.y) - (b.y - a.y) * (c.x - a.x)
+        }
+        HitShape::wind(&mut points);
+        //Points in a straight line would make triangles with no area
+        let mut i = 0;
+        while points.len() > 3 && i < points.len() {
+            let n = points.len();
+            if turn(points[(i + n - 1) % n], points[i], points[(i + 1) % n]).abs() < 1e-9 {
+                points.remove(i);
+            } else {
+                i += 1;
+            }
+        }
+        let mut parts = Vec::new();
+        while points.len() > 3 {
+            let (convex, ear) = {
+                let n = points.len();
+                let corner = |i: usize| (points[(i + n - 1) % n], points[i], points[(i + 1) % n]);
+                let convex = (0..n).all(|i| {
+                    let (a, b, c) = corner(i);
+                    turn(a, b, c) > 0.0
+                });
+                let ear = (0..n).find(|&i| {
+                    let (a, b, c) = corner(i);
+                    turn(a, b, c) > 0.0 && !points.iter().any(|&p| {
+                        p != a && p != b && p != c && turn(a, b, p) >= 0.0 && turn(b, c, p) >= 0.0 && turn(c, a, p) >= 0.0
+                    })
+                });
+                (convex, ear.map(|i| (i, corner(i))))
+            };
+            if convex {
+                break
+            }
+            match ear {
+                Some((i, (a, b, c))) => {
+                    parts.push(vec![a, b, c]);
+                    points.remove(i);
+                }
+                None => {
+                    println!("Hitbox polygon crosses itself, so using the hull of what's left of it");
+                    break
+                }
+            }
+        }
+        parts.push(points);
+        parts
+    }
+}
+
+#[derive(Clone)]
+struct Script {
+    path: String,
+    source: String,
+}
+
+impl Script {
+    fn read(path: String) -> Result<Script, String> {
+        use std::fs::File;
+        let mut source = String::new();
+        if let Err(error) = File::open(&path).and_then(|mut file| file.read_to_string(&mut source)) {
+            return Err(format!("Couldn't read script {}: {}", path, error))
+        }
+        Ok(Script {
+            path,
+            source,
+        })
+    }
+
+    ///Runs the script in a new environment that falls back to the globals, returning the environment
+    fn load(&self, lua: &Lua) -> rlua::Result<rlua::RegistryKey> {
+        let env = lua.create_table()?;
+        let meta = lua.create_table()?;
+        meta.set("__index", lua.globals())?;
+        env.set_metatable(Some(meta));
+        let load: rlua::Function = lua.globals().get("load")?;
+        let (chunk, error): (Option<rlua::Function>, Option<String>) = load.call((self.source.as_str(), format!("@{}", self.path), "t", env.clone()))?;
+        match chunk {
+            Some(chunk) => chunk.call::<_, ()>(())?,
+            None => return Err(rlua::Error::SyntaxError {
+                message: error.unwrap_or_default(),
+                incomplete_input: false,
+            })
+        }
+        lua.create_registry_value(env)
+    }
+}
+
+#[derive(Clone)]
 struct Frame {
     tile: usize,
     duration: u32,
 }
 
-struct Graphics<R: gfx::Resources, F: gfx::Factory<R>> {
+///Width of an atlas texture, and the height it's allowed to grow to
+const ATLAS_SIZE: u32 = 1024;
+
+pub struct Graphics<R: gfx::Resources, F: gfx::Factory<R>> {
     factory: F,
-    textures: IndexMap<String, Texture<R>>,
+    textures: IndexMap<String, Texture>,
     palettes: IndexMap<String, Palettes<R>>,
+    atlases: Vec<Atlas<R>>,
+    ///Texture index and optional sub-rectangle for each tile in `Tiles`
+    tile_images: Vec<(usize, Option<[u32; 4]>)>,
+    ///Where to draw each tile in `Tiles` from
+    tile_sprites: Vec<Sprite>,
 }
 
 impl<R: gfx::Resources, F: gfx::Factory<R>> Graphics<R, F> {
@@ -595,140 +1991,1183 @@ impl<R: gfx::Resources, F: gfx::Factory<R>> Graphics<R, F> {
             factory,
             textures: IndexMap::new(),
             palettes: IndexMap::new(),
+            atlases: Vec::new(),
+            tile_images: Vec::new(),
+            tile_sprites: Vec::new(),
+        }
+    }
+
+    ///Points every tile at its image, loading any not seen before, and packs everything into atlases again.
+    ///If an image or palette fails to load, the tiles keep pointing where they did.
+    fn load_tiles(&mut self, tiles: &Tiles) -> Result<(), String> {
+        let mut tile_images = Vec::new();
+        for tile in &tiles.tiles {
+            let texture = self.load_texture(&tile.image, &tile.palette, tile.palette_id, tile.transparent)?;
+            tile_images.push((texture, tile.rect));
+        }
+        self.tile_images = tile_images;
+        self.pack();
+        Ok(())
+    }
+
+    fn load_texture(&mut self, filename: &str, palette: &str, palette_id: usize, transparent: Option<usize>) -> Result<usize, String> {
+        let palette_index = self.load_palette(palette, transparent)?;
+        if let Some((index, _, _)) = self.textures.get_full(filename) {
+            return Ok(index)
+        }
+        let palette = self.palettes.get_index(palette_index).unwrap().1;
+        let texture = Texture::load(palette, palette_index, filename, palette_id)?;
+        self.textures.insert(filename.to_string(), texture);
+        Ok(self.textures.len() - 1)
+    }
+
+    ///Every tileset using a palette has to agree on its `transparent_index`, since they share one texture
+    fn load_palette(&mut self, filename: &str, transparent: Option<usize>) -> Result<usize, String> {
+        if let Some((index, _, palettes)) = self.palettes.get_full(filename) {
+            if palettes.transparent_index != transparent {
+                return Err(format!(
+                    "Palette {} is used with a transparent index of {:?} and of {:?}, but can only have one",
+                    filename, palettes.transparent_index, transparent
+                ))
+            }
+            return Ok(index)
+        }
+        let palettes = Palettes::load(&mut self.factory, filename, transparent)?;
+        self.palettes.insert(filename.to_string(), palettes);
+        Ok(self.palettes.len() - 1)
+    }
+
+    ///Packs every image into shelves of as few atlases as fit, one set per palette, then points the tile sprites at them
+    fn pack(&mut self) {
+        let mut order: Vec<usize> = (0..self.textures.len()).collect();
+        //Tallest first, so each shelf wastes as little height as possible
+        order.sort_by_key(|&index| {
+            let texture = self.get_texture(index);
+            (texture.palette, std::cmp::Reverse(texture.height))
+        });
+        let mut shelves: Vec<Shelves> = Vec::new();
+        for index in order {
+            let texture = self.textures.get_index_mut(index).unwrap().1;
+            let position = match shelves.last_mut() {
+                Some(last) => if last.palette == texture.palette {last.place(texture.width, texture.height)} else {None},
+                None => None
+            };
+            texture.position = match position {
+                Some(position) => position,
+                None => {
+                    let mut atlas = Shelves::new(texture.palette, texture.width.max(ATLAS_SIZE));
+                    let position = atlas.place(texture.width, texture.height).unwrap();
+                    shelves.push(atlas);
+                    position
+                }
+            };
+            texture.atlas = shelves.len() - 1;
         }
+
+        let mut pixels: Vec<Vec<u8>> = shelves.iter()
+            .map(|shelves| {
+                let transparent = self.get_palette(shelves.palette).transparent();
+                vec![transparent; (shelves.width * shelves.height()) as usize]
+            })
+            .collect();
+        for texture in self.textures.values() {
+            let width = shelves[texture.atlas].width;
+            let atlas = &mut pixels[texture.atlas];
+            for (y, row) in texture.pixels.chunks(texture.width as usize).enumerate() {
+                let start = ((texture.position.1 + y as u32) * width + texture.position.0) as usize;
+                atlas[start..start + row.len()].copy_from_slice(row);
+            }
+        }
+        {
+            let factory = &mut self.factory;
+            self.atlases = shelves.iter().zip(pixels)
+                .map(|(shelves, pixels)| Atlas::new(factory, shelves.palette, shelves.width, shelves.height(), pixels))
+                .collect();
+        }
+        println!("Packed {} images into {} atlases", self.textures.len(), self.atlases.len());
+
+        let textures = &self.textures;
+        let atlases = &self.atlases;
+        self.tile_sprites = self.tile_images.iter()
+            .map(|&(texture, rect)| {
+                let texture = textures.get_index(texture).unwrap().1;
+                let rect = rect.unwrap_or([0, 0, texture.width, texture.height]);
+                let atlas = &atlases[texture.atlas];
+                let rect = [texture.position.0 + rect[0], texture.position.1 + rect[1], rect[2], rect[3]];
+                Sprite::new(texture.atlas, (atlas.width, atlas.height), rect)
+            })
+            .collect();
     }
 
-    fn load_texture(&mut self, filename: &str, palette: &str, palette_id: usize) -> usize {
-        let palette_index = self.load_palette(palette);
-        let factory = &mut self.factory;
+    ///Reloads a palette or image that changed on disk, along with every texture using it
+    fn reload(&mut self, filename: &str) {
+        let palette_index = self.palettes.get_full(filename).map(|(index, _, _)| index);
+        if let Some(palette_index) = palette_index {
+            let transparent = self.get_palette(palette_index).transparent_index;
+            let palettes = match Palettes::load(&mut self.factory, filename, transparent) {
+                Ok(palettes) => palettes,
+                Err(error) => {
+                    println!("{}", error);
+                    return
+                }
+            };
+            *self.palettes.get_index_mut(palette_index).unwrap().1 = palettes;
+            let textures: Vec<String> = self.textures.iter()
+                .filter(|&(_, texture)| texture.palette == palette_index)
+                .map(|(path, _)| path.clone())
+                .collect();
+            for path in textures {
+                self.reload_texture(&path);
+            }
+        }
+        if filename.starts_with("assets/images/") {
+            let path = &filename["assets/images/".len()..];
+            if self.textures.contains_key(path) {
+                self.reload_texture(path);
+            }
+        }
+        self.pack();
+    }
+
+    fn reload_texture(&mut self, path: &str) {
+        let (palette_index, palette_id) = {
+            let texture = &self.textures[path];
+            (texture.palette, texture.palette_id)
+        };
         let palette = self.palettes.get_index(palette_index).unwrap().1;
-        let textures = &mut self.textures;
-        let entry = textures.entry(filename.to_string());
-        let index = entry.index();
-        entry.or_insert_with(|| Texture::load(factory, palette, palette_index, filename, palette_id));
-        index
+        //Keeps the old image if the new one can't be drawn with its palette
+        match Texture::load(palette, palette_index, path, palette_id) {
+            Ok(texture) => *self.textures.get_mut(path).unwrap() = texture,
+            Err(error) => println!("{}", error)
+        }
     }
 
-    fn load_palette(&mut self, filename: &str) -> usize {
-        let factory = &mut self.factory;
-        let entry = self.palettes.entry(filename.to_string());
-        let index = entry.index();
-        entry.or_insert_with(|| Palettes::load(factory, filename));
-        index
+    ///Every palette and image file loaded so far, as paths that can be watched for changes
+    fn watched_paths(&self) -> Vec<String> {
+        self.palettes.keys().cloned()
+            .chain(self.textures.keys().map(|path| format!("assets/images/{}", path)))
+            .collect()
     }
 
-    fn get_texture(&self, texture: usize) -> &Texture<R> {
+    ///Writes each atlas out as atlas0.png, atlas1.png and so on, coloured with the first row of its palette
+    fn dump_atlases(&self) {
+        for (index, atlas) in self.atlases.iter().enumerate() {
+            let colors = self.get_palette(atlas.palette).colors(0);
+            let image = image::RgbaImage::from_fn(atlas.width, atlas.height, |x, y| {
+                let pixel = atlas.pixels[(y * atlas.width + x) as usize] as usize;
+                colors.get(pixel).cloned().unwrap_or(image::Rgba { data: [0, 0, 0, 0] })
+            });
+            let path = format!("atlas{}.png", index);
+            match image.save(&path) {
+                Ok(()) => println!("Wrote {}", path),
+                Err(error) => println!("Unable to write {}: {}", path, error)
+            }
+        }
+    }
+
+    fn get_texture(&self, texture: usize) -> &Texture {
         self.textures.get_index(texture).unwrap().1
     }
 
+    fn get_atlas(&self, atlas: usize) -> &Atlas<R> {
+        &self.atlases[atlas]
+    }
+
+    fn tile_sprite(&self, tile: usize) -> &Sprite {
+        &self.tile_sprites[tile]
+    }
+
+    ///Brings every palette texture up to date with the cycles running at `time`
+    fn update_palettes<C: gfx::CommandBuffer<R>>(&mut self, encoder: &mut gfx::Encoder<R, C>, cycles: &[PaletteCycle], time: f64) {
+        for (path, palettes) in self.palettes.iter_mut() {
+            let cycles: Vec<_> = cycles.iter().filter(|cycle| &cycle.palette == path).collect();
+            palettes.update(encoder, &cycles, time);
+        }
+    }
+
     fn get_palette(&self, palette: usize) -> &Palettes<R> {
         self.palettes.get_index(palette).unwrap().1
     }
 }
 
-struct Texture<R: gfx::Resources> {
-    vertex_buffers: [gfx::handle::Buffer<R, Vertex>; 4],
+///Everything needed to draw a map, whether into a window or only offscreen
+struct Renderer<R: gfx::Resources, F: gfx::Factory<R>> {
+    graphics: Graphics<R, F>,
+    pso: gfx::PipelineState<R, pipe::Meta>,
+    blit_pso: gfx::PipelineState<R, blit::Meta>,
+    lines_pso: gfx::PipelineState<R, lines::Meta>,
+    batch: SpriteBatch<R>,
+    blit_vertices: DynamicBuffer<R, BlitVertex>,
+    debug_vertices: DynamicBuffer<R, DebugVertex>,
+    screen: Screen<R>,
+    data: pipe::Data<R>,
+}
+
+impl<R: gfx::Resources, F: gfx::Factory<R>> Renderer<R, F> {
+    fn new(mut factory: F, tiles: &Tiles) -> Renderer<R, F> {
+        let pso = factory.create_pipeline_simple(
+            include_bytes!("shaders/shader.vert"),
+            include_bytes!("shaders/shader.frag"),
+            pipe::new(),
+        ).unwrap();
+        let blit_pso = factory.create_pipeline_simple(
+            include_bytes!("shaders/blit.vert"),
+            include_bytes!("shaders/blit.frag"),
+            blit::new(),
+        ).unwrap();
+        let lines_shaders = factory.create_shader_set(
+            include_bytes!("shaders/lines.vert"),
+            include_bytes!("shaders/lines.frag"),
+        ).unwrap();
+        let lines_pso = factory.create_pipeline_state(
+            &lines_shaders,
+            gfx::Primitive::LineList,
+            gfx::state::Rasterizer::new_fill(),
+            lines::new(),
+        ).unwrap();
+
+        let palette_sampler = factory.create_sampler(gfx::texture::SamplerInfo::new(gfx::texture::FilterMethod::Scale, gfx::texture::WrapMode::Clamp));
+        let sampler = factory.create_sampler(gfx::texture::SamplerInfo::new(gfx::texture::FilterMethod::Scale, gfx::texture::WrapMode::Tile));
+
+        let mut graphics = Graphics::new(factory);
+        graphics.load_tiles(tiles).unwrap_or_else(|error| panic!("{}", error));
+
+        let screen = Screen::new(&mut graphics.factory, 1, 1);
+        let batch = SpriteBatch::new(&mut graphics.factory);
+        let blit_vertices = DynamicBuffer::new(&mut graphics.factory, 4);
+        let debug_vertices = DynamicBuffer::new(&mut graphics.factory, 1024);
+        let data = {
+            let atlas = graphics.get_atlas(0);
+
+            pipe::Data {
+                vbuf: batch.vertices.buffer.clone(),
+                palette: (graphics.get_palette(atlas.palette).texture(), palette_sampler),
+                sprite: (atlas.texture.clone(), sampler),
+                camera: [0.0, 0.0],
+                zoom: 1.0,
+                rotation: 0.0,
+                width: 0.0,
+                height: 0.0,
+                out: screen.target.clone(),
+            }
+        };
+
+        Renderer {
+            graphics,
+            pso,
+            blit_pso,
+            lines_pso,
+            batch,
+            blit_vertices,
+            debug_vertices,
+            screen,
+            data,
+        }
+    }
+
+    ///Draws the map as the camera sees it into the offscreen screen, `alpha` of the way from the last tick to the current one
+    fn draw<C: gfx::CommandBuffer<R>>(&mut self, encoder: &mut gfx::Encoder<R, C>, lua: &Lua, map: &Map, tiles: &Tiles, counter: f64, alpha: f64) {
+        let graphics = &mut self.graphics;
+        let batch = &mut self.batch;
+        let pso = &self.pso;
+        let data = &mut self.data;
+        graphics.update_palettes(encoder, &map.palette_cycles, counter);
+        {
+            let camera: rlua::AnyUserData = lua.globals().get("camera").expect("Unable to get camera");
+            let camera = camera.borrow::<camera::Camera>().expect("camera should be a camera");
+            let (x, y) = camera.position(alpha);
+            //Whole pixels at the current zoom, so sprites don't shimmer as the camera moves
+            data.camera = [((x * camera.zoom).round() / camera.zoom) as f32, ((y * camera.zoom).round() / camera.zoom) as f32];
+            data.zoom = camera.zoom as f32;
+            data.rotation = camera.rotation as f32;
+            data.width = camera.width as f32;
+            data.height = camera.height as f32;
+            let (width, height) = (camera.width.round() as u16, camera.height.round() as u16);
+            if (width, height) != (self.screen.width, self.screen.height) {
+                self.screen = Screen::new(&mut graphics.factory, width, height);
+                data.out = self.screen.target.clone();
+            }
+        }
+        encoder.clear(&data.out, map.color);
+        //Layers are drawn back to front, with objects after every layer not marked as foreground
+        for &foreground in &[false, true] {
+            for layer in map.layers.iter().filter(|layer| layer.visible && layer.foreground == foreground) {
+                for (&(x, y), map_tile) in &layer.tiles {
+                    let sprite = graphics.tile_sprite(tiles.frame(map_tile.tile, counter));
+                    let x = x * map.info.tile_width as i32;
+                    let y = y * map.info.tile_height as i32;
+                    batch.add(sprite, x as f32, y as f32, map_tile.rotation, map_tile.flipped, map_tile.palette);
+                }
+                batch.draw(graphics, encoder, pso, data);
+            }
+            if foreground {
+                continue
+            }
+            for &(tile_id, ref object) in map.objects.values() {
+                let sprite = graphics.tile_sprite(tiles.frame(tile_id, counter));
+                let x = (object.last_x + (object.x - object.last_x) * alpha).round();
+                let y = (object.last_y + (object.y - object.last_y) * alpha).round();
+                batch.add(sprite, x as f32, y as f32, object.rotation, object.flipped, object.palette);
+            }
+            batch.draw(graphics, encoder, pso, data);
+        }
+        if let Some(ref font) = map.font {
+            //The last script error goes over whatever scripts drew
+            let error = map.last_error.as_ref().map(|error| text::Text::overlay(error, data.width as f64));
+            for text in map.text.iter().chain(error.iter()) {
+                for (tile, x, y) in text.layout(font) {
+                    let sprite = graphics.tile_sprite(tiles.frame(tile, counter));
+                    batch.add(sprite, x as f32, y as f32, 0, false, text.palette);
+                }
+            }
+            //Text is placed on the view rather than in the world
+            let view = (data.camera, data.zoom, data.rotation);
+            data.camera = [data.width / 2.0, data.height / 2.0];
+            data.zoom = 1.0;
+            data.rotation = 0.0;
+            batch.draw(graphics, encoder, pso, data);
+            data.camera = view.0;
+            data.zoom = view.1;
+            data.rotation = view.2;
+        }
+    }
+
+    ///Draws collision shapes, contacts and object labels over whatever `draw` left on the screen
+    fn draw_debug<C: gfx::CommandBuffer<R>>(&mut self, encoder: &mut gfx::Encoder<R, C>, map: &Map) {
+        let vertices = debug::lines(map);
+        if vertices.is_empty() {
+            return
+        }
+        let vbuf = self.debug_vertices.write(&mut self.graphics.factory, encoder, &vertices);
+        let slice = gfx::Slice {
+            start: 0,
+            end: vertices.len() as u32,
+            base_vertex: 0,
+            instances: None,
+            buffer: gfx::IndexBuffer::Auto,
+        };
+        let data = lines::Data {
+            vbuf,
+            camera: self.data.camera,
+            zoom: self.data.zoom,
+            rotation: self.data.rotation,
+            width: self.data.width,
+            height: self.data.height,
+            out: self.data.out.clone(),
+        };
+        encoder.draw(&slice, &self.lines_pso, &data);
+    }
+
+    ///Scales the screen into `target`, which is `size` pixels big, leaving letterbox bars in `background`
+    fn present<C: gfx::CommandBuffer<R>>(&mut self, encoder: &mut gfx::Encoder<R, C>, target: &gfx::handle::RenderTargetView<R, ColorFormat>, size: (u32, u32), scaling: Scaling, background: [f32; 4]) {
+        encoder.clear(target, background);
+        let quad = self.screen.quad(scaling, size);
+        let vbuf = self.blit_vertices.write(&mut self.graphics.factory, encoder, &quad);
+        let slice = self.batch.indices.slice(&mut self.graphics.factory, 1);
+        let data = blit::Data {
+            vbuf,
+            screen: (self.screen.view.clone(), self.data.palette.1.clone()),
+            out: target.clone(),
+        };
+        encoder.draw(&slice, &self.blit_pso, &data);
+    }
+
+    ///Reads back everything drawn to the screen so far, flushing the encoder to get it
+    fn capture<C, D>(&mut self, encoder: &mut gfx::Encoder<R, C>, device: &mut D) -> image::RgbaImage
+        where C: gfx::CommandBuffer<R>, D: gfx::Device<Resources = R, CommandBuffer = C> {
+        let (width, height) = (self.screen.width as u32, self.screen.height as u32);
+        let factory = &mut self.graphics.factory;
+        let buffer = factory.create_download_buffer::<[u8; 4]>((width * height) as usize).unwrap();
+        let info = self.screen.texture.get_info().to_raw_image_info(gfx::format::ChannelType::Srgb, 0);
+        encoder.copy_texture_to_buffer_raw(self.screen.texture.raw(), None, info, buffer.raw(), 0).unwrap();
+        encoder.flush(device);
+        let pixels = factory.read_mapping(&buffer).unwrap();
+        //GL keeps the bottom row first
+        image::RgbaImage::from_fn(width, height, |x, y| image::Rgba { data: pixels[((height - 1 - y) * width + x) as usize] })
+    }
+}
+
+///Ways of fitting the view into a window of a different size
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Scaling {
+    ///As big as fits while keeping its shape
+    Fit,
+    ///As big as fits in whole multiples of its size, so every pixel stays the same size
+    Integer,
+    ///Filling the whole window, whatever its shape
+    Stretch,
+}
+
+impl Scaling {
+    ///Reads the map's `scaling` property, defaulting to fit
+    fn from_properties(properties: &tiled::Properties) -> Scaling {
+        match properties.get("scaling") {
+            Some(&tiled::PropertyValue::StringValue(ref mode)) => match mode.as_str() {
+                "fit" => Scaling::Fit,
+                "integer" => Scaling::Integer,
+                "stretch" => Scaling::Stretch,
+                mode => {
+                    println!("Unknown scaling {}, using fit", mode);
+                    Scaling::Fit
+                }
+            },
+            _ => Scaling::Fit
+        }
+    }
+
+    ///Left, top, right and bottom edges, in window pixels, of a view `size` big scaled into `window`
+    fn place(self, size: (f32, f32), window: (f32, f32)) -> (f32, f32, f32, f32) {
+        let ((width, height), (window_width, window_height)) = (size, window);
+        let (scale_x, scale_y) = match self {
+            Scaling::Fit => {
+                let scale = (window_width / width).min(window_height / height);
+                (scale, scale)
+            }
+            Scaling::Integer => {
+                //Views bigger than the window are cropped rather than shrunk
+                let scale = (window_width / width).min(window_height / height).floor().max(1.0);
+                (scale, scale)
+            }
+            Scaling::Stretch => (window_width / width, window_height / height)
+        };
+        //Whole pixel edges, so integer scaling doesn't land between pixels
+        let left = ((window_width - width * scale_x) / 2.0).round();
+        let top = ((window_height - height * scale_y) / 2.0).round();
+        (left, top, left + (width * scale_x).round(), top + (height * scale_y).round())
+    }
+
+    fn next(self) -> Scaling {
+        match self {
+            Scaling::Fit => Scaling::Integer,
+            Scaling::Integer => Scaling::Stretch,
+            Scaling::Stretch => Scaling::Fit,
+        }
+    }
+}
+
+///The offscreen target the view is drawn into at its own resolution before being scaled into the window
+struct Screen<R: gfx::Resources> {
+    width: u16,
+    height: u16,
+    texture: gfx::handle::Texture<R, <ColorFormat as gfx::format::Formatted>::Surface>,
+    view: ShaderResourceView<R, [f32; 4]>,
+    target: gfx::handle::RenderTargetView<R, ColorFormat>,
+}
+
+impl<R: gfx::Resources> Screen<R> {
+    fn new<F: gfx::Factory<R>>(factory: &mut F, width: u16, height: u16) -> Screen<R> {
+        let kind = gfx::texture::Kind::D2(width, height, gfx::texture::AaMode::Single);
+        //Readable as well, for `Renderer::capture`
+        let bind = gfx::memory::Bind::RENDER_TARGET | gfx::memory::Bind::SHADER_RESOURCE | gfx::memory::Bind::TRANSFER_SRC;
+        let texture = factory.create_texture(kind, 1, bind, gfx::memory::Usage::Data, Some(gfx::format::ChannelType::Srgb)).unwrap();
+        let view = factory.view_texture_as_shader_resource::<ColorFormat>(&texture, (0, 0), gfx::format::Swizzle::new()).unwrap();
+        let target = factory.view_texture_as_render_target(&texture, 0, None).unwrap();
+        Screen {
+            width,
+            height,
+            texture,
+            view,
+            target,
+        }
+    }
+
+    ///The quad covering the part of a window `window` pixels big that the view gets scaled into
+    fn quad(&self, scaling: Scaling, window: (u32, u32)) -> [BlitVertex; 4] {
+        let (window_width, window_height) = (window.0.max(1) as f32, window.1.max(1) as f32);
+        let (left, top, right, bottom) = scaling.place((self.width as f32, self.height as f32), (window_width, window_height));
+        let (left, right) = (left / window_width * 2.0 - 1.0, right / window_width * 2.0 - 1.0);
+        let (top, bottom) = (1.0 - top / window_height * 2.0, 1.0 - bottom / window_height * 2.0);
+        [
+            BlitVertex { pos: [right, top], uv: [1.0, 1.0] },
+            BlitVertex { pos: [left, top], uv: [0.0, 1.0] },
+            BlitVertex { pos: [left, bottom], uv: [0.0, 0.0] },
+            BlitVertex { pos: [right, bottom], uv: [1.0, 0.0] },
+        ]
+    }
+}
+
+///Packs images into rows, each as tall as the tallest image in it
+struct Shelves {
+    palette: usize,
+    width: u32,
+    ///Top of the current row
+    y: u32,
+    ///Where the next image in the current row goes
+    x: u32,
+    row_height: u32,
+}
+
+impl Shelves {
+    fn new(palette: usize, width: u32) -> Shelves {
+        Shelves {
+            palette,
+            width,
+            y: 0,
+            x: 0,
+            row_height: 0,
+        }
+    }
+
+    ///The top left corner for an image, or None if the atlas is full
+    fn place(&mut self, width: u32, height: u32) -> Option<(u32, u32)> {
+        if self.x + width > self.width {
+            self.y += self.row_height;
+            self.x = 0;
+            self.row_height = 0;
+        }
+        //An image too big for any atlas still gets an atlas of its own
+        let empty = self.x == 0 && self.y == 0;
+        if !empty && (self.x + width > self.width || self.y + height > ATLAS_SIZE) {
+            return None
+        }
+        let position = (self.x, self.y);
+        self.x += width;
+        self.row_height = self.row_height.max(height);
+        Some(position)
+    }
+
+    fn height(&self) -> u32 {
+        self.y + self.row_height
+    }
+}
+
+///Indexed images packed together into one texture
+struct Atlas<R: gfx::Resources> {
+    palette: usize,
+    width: u32,
+    height: u32,
+    ///Kept around for `Graphics::dump_atlases`
+    pixels: Vec<u8>,
     texture: gfx::handle::ShaderResourceView<R, u32>,
+}
+
+impl<R: gfx::Resources> Atlas<R> {
+    fn new<F: gfx::Factory<R>>(factory: &mut F, palette: usize, width: u32, height: u32, pixels: Vec<u8>) -> Atlas<R> {
+        let kind = gfx::texture::Kind::D2(width as u16, height as u16, gfx::texture::AaMode::Single);
+        let (_, texture) = factory.create_texture_immutable_u8::<TextureColorFormat>(kind, gfx::texture::Mipmap::Provided, &[&pixels]).unwrap();
+        Atlas {
+            palette,
+            width,
+            height,
+            pixels,
+            texture,
+        }
+    }
+}
+
+///Part of an atlas, with quads for drawing it in each rotation
+struct Sprite {
+    atlas: usize,
+    ///One quad for each rotation, then the same again flipped, with the top left corner at 0, 0
+    quads: Vec<[Vertex; 4]>,
+}
+
+impl Sprite {
+    ///`rect` is the x, y, width and height of the sprite in an atlas that's `size` pixels big
+    fn new(atlas: usize, size: (u32, u32), rect: [u32; 4]) -> Sprite {
+        let left = rect[0] as f32 / size.0 as f32;
+        let right = (rect[0] + rect[2]) as f32 / size.0 as f32;
+        let top = rect[1] as f32 / size.1 as f32;
+        let bottom = (rect[1] + rect[3]) as f32 / size.1 as f32;
+        let width = rect[2] as f32;
+        let height = rect[3] as f32;
+        let mut quads = Vec::new();
+        for &flipped in &[false, true] {
+            let (left, right) = if flipped {(right, left)} else {(left, right)};
+            quads.push([
+                Vertex { pos: [width, 0.0], uv: [right, top], palette: 0.0 },
+                Vertex { pos: [0.0, 0.0], uv: [left, top], palette: 0.0 },
+                Vertex { pos: [0.0, height], uv: [left, bottom], palette: 0.0 },
+                Vertex { pos: [width, height], uv: [right, bottom], palette: 0.0 },
+            ]);
+            quads.push([
+                Vertex { pos: [height, 0.0], uv: [left, top], palette: 0.0 },
+                Vertex { pos: [0.0, 0.0], uv: [left, bottom], palette: 0.0 },
+                Vertex { pos: [0.0, width], uv: [right, bottom], palette: 0.0 },
+                Vertex { pos: [height, width], uv: [right, top], palette: 0.0 },
+            ]);
+            quads.push([
+                Vertex { pos: [width, 0.0], uv: [left, bottom], palette: 0.0 },
+                Vertex { pos: [0.0, 0.0], uv: [right, bottom], palette: 0.0 },
+                Vertex { pos: [0.0, height], uv: [right, top], palette: 0.0 },
+                Vertex { pos: [width, height], uv: [left, top], palette: 0.0 },
+            ]);
+            quads.push([
+                Vertex { pos: [height, 0.0], uv: [right, bottom], palette: 0.0 },
+                Vertex { pos: [0.0, 0.0], uv: [right, top], palette: 0.0 },
+                Vertex { pos: [0.0, width], uv: [left, top], palette: 0.0 },
+                Vertex { pos: [height, width], uv: [left, bottom], palette: 0.0 },
+            ]);
+        }
+        Sprite {
+            atlas,
+            quads,
+        }
+    }
+
+    fn quad(&self, rotation: usize, flipped: bool) -> &[Vertex; 4] {
+        &self.quads[rotation + if flipped {4} else {0}]
+    }
+}
+
+///Vertices kept in one buffer between frames and written again for each draw, only replaced once they outgrow it
+struct DynamicBuffer<R: gfx::Resources, V> {
+    buffer: gfx::handle::Buffer<R, V>,
+}
+
+impl<R: gfx::Resources, V: gfx::traits::Pod> DynamicBuffer<R, V> {
+    fn new<F: gfx::Factory<R>>(factory: &mut F, size: usize) -> DynamicBuffer<R, V> {
+        DynamicBuffer {
+            buffer: factory.create_buffer(size, gfx::buffer::Role::Vertex, gfx::memory::Usage::Dynamic, gfx::memory::Bind::empty())
+                .expect("Unable to create vertex buffer"),
+        }
+    }
+
+    ///Copies `vertices` to the start of the buffer, first swapping in a bigger one if they don't fit
+    fn write<F, C>(&mut self, factory: &mut F, encoder: &mut gfx::Encoder<R, C>, vertices: &[V]) -> gfx::handle::Buffer<R, V>
+        where F: gfx::Factory<R>, C: gfx::CommandBuffer<R> {
+        if vertices.len() > self.buffer.len() {
+            *self = DynamicBuffer::new(factory, vertices.len().next_power_of_two());
+        }
+        encoder.update_buffer(&self.buffer, vertices, 0).expect("Unable to update vertex buffer");
+        self.buffer.clone()
+    }
+}
+
+///Indices for drawing quads of four vertices as two triangles each, made once for as many quads as have been drawn at a time
+struct QuadIndices<R: gfx::Resources> {
+    buffer: gfx::IndexBuffer<R>,
+    quads: usize,
+}
+
+impl<R: gfx::Resources> QuadIndices<R> {
+    fn new<F: gfx::Factory<R>>(factory: &mut F, quads: usize) -> QuadIndices<R> {
+        let indices: Vec<u32> = (0..quads as u32)
+            .flat_map(|quad| INDICES.iter().map(move |&index| quad * 4 + index))
+            .collect();
+        QuadIndices {
+            buffer: factory.create_index_buffer(&indices[..]),
+            quads,
+        }
+    }
+
+    ///A slice drawing the first `quads` quads, making the indices again if there aren't enough
+    fn slice<F: gfx::Factory<R>>(&mut self, factory: &mut F, quads: usize) -> gfx::Slice<R> {
+        if quads > self.quads {
+            *self = QuadIndices::new(factory, quads.next_power_of_two());
+        }
+        gfx::Slice {
+            start: 0,
+            end: quads as u32 * INDICES.len() as u32,
+            base_vertex: 0,
+            instances: None,
+            buffer: self.buffer.clone(),
+        }
+    }
+}
+
+///Sprites gathered up so each atlas is drawn with one call
+struct SpriteBatch<R: gfx::Resources> {
+    ///Four vertices per sprite, for each atlas
+    quads: Vec<Vec<Vertex>>,
+    vertices: DynamicBuffer<R, Vertex>,
+    indices: QuadIndices<R>,
+}
+
+impl<R: gfx::Resources> SpriteBatch<R> {
+    ///Room for this many sprites in one draw before the buffers have to grow
+    const SPRITES: usize = 1024;
+
+    fn new<F: gfx::Factory<R>>(factory: &mut F) -> SpriteBatch<R> {
+        SpriteBatch {
+            quads: Vec::new(),
+            vertices: DynamicBuffer::new(factory, SpriteBatch::<R>::SPRITES * 4),
+            indices: QuadIndices::new(factory, SpriteBatch::<R>::SPRITES),
+        }
+    }
+
+    fn add(&mut self, sprite: &Sprite, x: f32, y: f32, rotation: usize, flipped: bool, palette: usize) {
+        if self.quads.len() <= sprite.atlas {
+            self.quads.resize(sprite.atlas + 1, Vec::new());
+        }
+        for vertex in sprite.quad(rotation, flipped) {
+            self.quads[sprite.atlas].push(Vertex {
+                pos: [vertex.pos[0] + x, vertex.pos[1] + y],
+                uv: vertex.uv,
+                palette: palette as f32,
+            });
+        }
+    }
+
+    ///Draws everything added since the last call
+    fn draw<F, C>(&mut self, graphics: &mut Graphics<R, F>, encoder: &mut gfx::Encoder<R, C>, pso: &gfx::PipelineState<R, pipe::Meta>, data: &mut pipe::Data<R>)
+        where F: gfx::Factory<R>, C: gfx::CommandBuffer<R> {
+        for (atlas, vertices) in self.quads.iter_mut().enumerate() {
+            if vertices.is_empty() {
+                continue
+            }
+            data.vbuf = self.vertices.write(&mut graphics.factory, encoder, vertices);
+            let slice = self.indices.slice(&mut graphics.factory, vertices.len() / 4);
+            let atlas = graphics.get_atlas(atlas);
+            data.sprite.0 = atlas.texture.clone();
+            data.palette.0 = graphics.get_palette(atlas.palette).texture();
+            encoder.draw(&slice, pso, data);
+            vertices.clear();
+        }
+    }
+}
+
+///An image matched against its palette, and where it ended up in the atlases
+struct Texture {
+    width: u32,
+    height: u32,
+    ///One palette index per pixel
+    pixels: Vec<u8>,
     palette: usize,
+    ///Which row of the palette image the texture was matched against
+    palette_id: usize,
+    atlas: usize,
+    ///Top left corner of the image in its atlas
+    position: (u32, u32),
 }
 
-impl<R: gfx::Resources> Texture<R> {
-    fn load<F: gfx::Factory<R>>(factory: &mut F, palettes: &Palettes<R>, palette_index: usize, path: &str, palette_id: usize) -> Texture<R> {
+impl Texture {
+    ///Matches every opaque pixel against a row of the palette, failing if any colour isn't in it
+    fn load<R: gfx::Resources>(palettes: &Palettes<R>, palette_index: usize, path: &str, palette_id: usize) -> Result<Texture, String> {
         let path = format!("assets/images/{}", path);
-        let img = image::open(&path).expect(&path).to_rgba();
+        let img = image::open(&path).map_err(|error| format!("Unable to open {}: {}", path, error))?.to_rgba();
         let (width, height) = img.dimensions();
-        let kind = gfx::texture::Kind::D2(width as u16, height as u16, gfx::texture::AaMode::Single);
-        let mut missing = HashMap::new();
-        let palette_lookup = &palettes.palettes[palette_id].0;
+        let mut missing = IndexMap::new();
+        let palette_lookup = palettes.palettes.get(palette_id)
+            .ok_or_else(|| format!("{} uses palette row {}, but its palette only has {} rows", path, palette_id, palettes.palettes.len()))?;
         let mut data = Vec::new();
         for (x, y, pixel) in img.enumerate_pixels() {
             if pixel[3] == 0xFF {
                 if let Some(index) = palette_lookup.get(&pixel) {
                     data.push(*index as u8)
                 } else {
-                    missing.entry(pixel.clone()).or_insert_with(|| Vec::new()).push((x, y));
-                    data.push(palette_lookup.len() as u8)
+                    missing.entry(pixel.data).or_insert((x, y));
+                    data.push(palettes.transparent())
                 }
             } else {
-                data.push(palette_lookup.len() as u8)
+                data.push(palettes.transparent())
             }
         }
-        for pixel in missing {
-            println!("Missing color: {:?}", pixel);
+        if !missing.is_empty() {
+            let colors: Vec<String> = missing.iter()
+                .map(|(color, &(x, y))| format!("#{:02x}{:02x}{:02x} first at ({}, {})", color[0], color[1], color[2], x, y))
+                .collect();
+            return Err(format!(
+                "{} uses {} colours that aren't in row {} of its {} colour palette: {}",
+                path, missing.len(), palette_id, palettes.size, colors.join(", ")
+            ))
         }
-        let (_, view) = factory.create_texture_immutable_u8::<TextureColorFormat>(kind, gfx::texture::Mipmap::Provided, &[&data]).unwrap();
 
-        let width = width as f32;
-        let height = height as f32;
         println!("{}: {}, {}", path, width, height);
 
-        let vertex_buffers = [
-            factory.create_vertex_buffer(&[
-                Vertex { pos: [width, 0.0], uv: [1.0, 0.0] },
-                Vertex { pos: [0.0, 0.0], uv: [0.0, 0.0] },
-                Vertex { pos: [0.0, height], uv: [0.0, 1.0] },
-                Vertex { pos: [width, height], uv: [1.0, 1.0] },
-            ]),
-            factory.create_vertex_buffer(&[
-                Vertex { pos: [height, 0.0], uv: [0.0, 0.0] },
-                Vertex { pos: [0.0, 0.0], uv: [0.0, 1.0] },
-                Vertex { pos: [0.0, width], uv: [1.0, 1.0] },
-                Vertex { pos: [height, width], uv: [1.0, 0.0] },
-            ]),
-            factory.create_vertex_buffer(&[
-                Vertex { pos: [width, 0.0], uv: [0.0, 1.0] },
-                Vertex { pos: [0.0, 0.0], uv: [1.0, 1.0] },
-                Vertex { pos: [0.0, height], uv: [1.0, 0.0] },
-                Vertex { pos: [width, height], uv: [0.0, 0.0] },
-            ]),
-            factory.create_vertex_buffer(&[
-                Vertex { pos: [height, 0.0], uv: [1.0, 1.0] },
-                Vertex { pos: [0.0, 0.0], uv: [1.0, 0.0] },
-                Vertex { pos: [0.0, width], uv: [0.0, 0.0] },
-                Vertex { pos: [height, width], uv: [0.0, 1.0] },
-            ]),
-        ];
-        Texture {
-            vertex_buffers,
-            texture: view,
+        Ok(Texture {
+            width,
+            height,
+            pixels: data,
             palette: palette_index,
+            palette_id,
+            atlas: 0,
+            position: (0, 0),
+        })
+    }
+}
+
+///Most colours a palette row can have, including the transparent entry, so every index fits in a byte
+const MAX_PALETTE_SIZE: usize = 256;
+
+///A range of palette indices whose colours rotate over time, in every row of the palette
+#[derive(Debug, Clone, PartialEq)]
+struct PaletteCycle {
+    ///Path of the palette image, matching `Tile::palette`
+    palette: String,
+    start: usize,
+    ///Last index in the range, not one past it
+    end: usize,
+    ///Indices moved per second, backwards if negative
+    speed: f64,
+}
+
+impl PaletteCycle {
+    ///Reads a tileset's `palette_cycle` property, made of `start end speed` triples separated by semicolons,
+    ///for a palette with `size` entries
+    fn parse(palette: &str, size: usize, property: &str) -> Result<Vec<PaletteCycle>, String> {
+        let mut cycles = Vec::new();
+        for cycle in property.split(';').filter(|cycle| !cycle.trim().is_empty()) {
+            let parts: Vec<&str> = cycle.split_whitespace().collect();
+            let parsed = match (parts.get(0).map(|part| part.parse()), parts.get(1).map(|part| part.parse()), parts.get(2).map(|part| part.parse())) {
+                (Some(Ok(start)), Some(Ok(end)), Some(Ok(speed))) if parts.len() == 3 => PaletteCycle { palette: palette.to_string(), start, end, speed },
+                _ => return Err(format!("palette_cycle should be start, end and speed, not {}", cycle))
+            };
+            parsed.check(size)?;
+            cycles.push(parsed);
         }
+        Ok(cycles)
+    }
+
+    ///Makes sure the range covers at least two entries of a palette with `size` of them
+    fn check(&self, size: usize) -> Result<(), String> {
+        if self.start >= self.end {
+            return Err(format!("Palette cycle from {} to {} on {} needs to end after it starts", self.start, self.end, self.palette))
+        }
+        if self.end >= size {
+            return Err(format!("Palette cycle from {} to {} goes past the end of {}, which has {} colours", self.start, self.end, self.palette, size))
+        }
+        Ok(())
+    }
+
+    ///Adds the cycle, replacing any over the same range of the same palette. A speed of 0 just stops the range cycling.
+    fn insert_into(self, cycles: &mut Vec<PaletteCycle>) {
+        cycles.retain(|other| (&other.palette, other.start, other.end) != (&self.palette, self.start, self.end));
+        if self.speed != 0.0 {
+            cycles.push(self);
+        }
+    }
+
+    ///How many places along the range the colours have moved `time` seconds in
+    fn offset(&self, time: f64) -> usize {
+        let length = (self.end + 1 - self.start) as i64;
+        let steps = (time * self.speed).floor() as i64;
+        (((steps % length) + length) % length) as usize
     }
 }
 
+///Entries in a palette: the width of its image, plus one for transparency unless there's a `transparent_index`
+fn palette_size(filename: &str, transparent_index: Option<usize>) -> Result<usize, String> {
+    use image::GenericImage;
+    let width = image::open(filename).map_err(|error| format!("Unable to open palette {}: {}", filename, error))?.width() as usize;
+    Ok(if transparent_index.is_some() {width} else {width + 1})
+}
+
 struct Palettes<R: gfx::Resources> {
-    palettes: Vec<(HashMap<image::Rgba<u8>, usize>, ShaderResourceView<R, [f32; 4]>)>
+    ///Index of each colour, for every row of the palette image
+    palettes: Vec<HashMap<image::Rgba<u8>, usize>>,
+    ///Entries in each row, which is the width of the palette image plus one for transparency unless `transparent_index` is set
+    size: usize,
+    ///The index given by the tileset to draw as transparent, if any
+    transparent_index: Option<usize>,
+    ///Colours of every row, `size` to a row, before any cycling
+    colors: Vec<[u8; 4]>,
+    raw: gfx::handle::Texture<R, <ColorFormat as gfx::format::Formatted>::Surface>,
+    ///Every row of the palette image, as one row of the texture each
+    texture: ShaderResourceView<R, [f32; 4]>,
+    ///The range and offset of each cycle in the texture now, or None if nothing has been uploaded yet
+    cycled: Option<Vec<(usize, usize, usize)>>,
 }
 
 impl<R: gfx::Resources> Palettes<R> {
-    fn load<F: gfx::Factory<R>>(factory: &mut F, filename: &str) -> Palettes<R> {
-        let img = image::open(filename).expect("Unable to open palette image");
-        let img = img.as_rgba8().expect("Unable to convert to RGBA image");
+    ///Without a `transparent_index`, an extra transparent entry goes after the colours in the image
+    fn load<F: gfx::Factory<R>>(factory: &mut F, filename: &str, transparent_index: Option<usize>) -> Result<Palettes<R>, String> {
+        let img = image::open(filename).map_err(|error| format!("Unable to open palette {}: {}", filename, error))?.to_rgba();
+        let width = img.width() as usize;
+        let size = if transparent_index.is_some() {width} else {width + 1};
+        if size > MAX_PALETTE_SIZE {
+            return Err(format!("Palette {} has {} colours, but palettes can only have {}", filename, size, MAX_PALETTE_SIZE))
+        }
+        if let Some(index) = transparent_index {
+            if index >= size {
+                return Err(format!("Transparent index {} is past the end of palette {}, which has {} colours", index, filename, size))
+            }
+        }
+        let transparent = transparent_index.unwrap_or(width);
         let mut palettes = Vec::new();
+        let mut colors = Vec::new();
         for y in 0..img.height() {
             let mut palette_lookup = HashMap::new();
             let mut palette = Vec::new();
             for x in 0..img.width() {
                 let color = *img.get_pixel(x, y);
-                palette_lookup.insert(color, x as usize);
-                palette.push(color.data[0]);
-                palette.push(color.data[1]);
-                palette.push(color.data[2]);
-                palette.push(color.data[3]);
+                palette_lookup.entry(color).or_insert(x as usize);
+                palette.push(color.data);
             }
-            palette.push(0);
-            palette.push(0);
-            palette.push(0);
-            palette.push(0);
-            let (_, palette_texture) = factory.create_texture_immutable_u8::<ColorFormat>(gfx::texture::Kind::D1(64), gfx::texture::Mipmap::Provided, &[palette.as_slice()]).unwrap();
-            palettes.push((palette_lookup, palette_texture));
+            palette.resize(size, [0, 0, 0, 0]);
+            palette[transparent] = [0, 0, 0, 0];
+            colors.extend(palette);
+            palettes.push(palette_lookup);
+        }
+        //Written by `Graphics::update_palettes` rather than here, since cycling rewrites it as it goes
+        let kind = gfx::texture::Kind::D2(size as u16, img.height() as u16, gfx::texture::AaMode::Single);
+        let bind = gfx::memory::Bind::SHADER_RESOURCE | gfx::memory::Bind::TRANSFER_DST;
+        let raw = factory.create_texture(kind, 1, bind, gfx::memory::Usage::Dynamic, Some(gfx::format::ChannelType::Srgb)).unwrap();
+        let texture = factory.view_texture_as_shader_resource::<ColorFormat>(&raw, (0, 0), gfx::format::Swizzle::new()).unwrap();
+        Ok(Palettes {
+            palettes,
+            size,
+            transparent_index,
+            colors,
+            raw,
+            texture,
+            cycled: None,
+        })
+    }
+
+    ///Uploads the palette with `cycles` applied at `time`, unless the texture already has them
+    fn update<C: gfx::CommandBuffer<R>>(&mut self, encoder: &mut gfx::Encoder<R, C>, cycles: &[&PaletteCycle], time: f64) {
+        let cycled: Vec<_> = cycles.iter()
+            .filter(|cycle| cycle.start < cycle.end && cycle.end < self.size)
+            .map(|cycle| (cycle.start, cycle.end, cycle.offset(time)))
+            .collect();
+        if self.cycled.as_ref() == Some(&cycled) {
+            return
         }
-        Palettes {
-            palettes
+        let mut colors = self.colors.clone();
+        for row in colors.chunks_mut(self.size) {
+            for &(start, end, offset) in &cycled {
+                row[start..end + 1].rotate_right(offset);
+            }
         }
+        let info = self.raw.get_info().to_image_info(0);
+        encoder.update_texture::<<ColorFormat as gfx::format::Formatted>::Surface, ColorFormat>(&self.raw, None, info, &colors).unwrap();
+        self.cycled = Some(cycled);
+    }
+
+    fn texture(&self) -> ShaderResourceView<R, [f32; 4]> {
+        self.texture.clone()
     }
-    fn get(&self, index: usize) -> ShaderResourceView<R, [f32; 4]> {
-        self.palettes[index].1.clone()
+
+    ///The index images use for transparent pixels
+    fn transparent(&self) -> u8 {
+        self.transparent_index.unwrap_or(self.size - 1) as u8
+    }
+
+    ///The colours of one palette row, in index order
+    fn colors(&self, index: usize) -> Vec<image::Rgba<u8>> {
+        self.colors[index * self.size..(index + 1) * self.size].iter()
+            .map(|&color| image::Rgba { data: color })
+            .collect()
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    pub const FINITE: &str = "assets/tiled/Finite.tmx";
+
+    ///Loads Finite.tmx with a fresh set of tiles, for tests that need a whole map
+    pub fn load_finite(lua: &Lua) -> (Tiles, Map) {
+        let mut tiles = Tiles::new(lua);
+        let map = Map::load(&mut tiles, FINITE).unwrap();
+        (tiles, map)
+    }
+
+    #[test]
+    fn reloading_tilesets_keeps_their_tiles_in_place() {
+        let lua = Lua::new();
+        let (mut tiles, map) = load_finite(&lua);
+        let (count, offsets) = (tiles.tiles.len(), tiles.offsets.clone());
+        tiles.reload_tilesets();
+        let reloaded = Map::load(&mut tiles, FINITE).unwrap();
+        assert_eq!(tiles.tiles.len(), count);
+        assert_eq!(tiles.offsets, offsets);
+        assert_eq!(reloaded.tile_lookup, map.tile_lookup);
+    }
+
+    #[test]
+    fn tilesets_that_change_size_only_move_once() {
+        let lua = Lua::new();
+        let (mut tiles, _map) = load_finite(&lua);
+        //As if the first tileset had gained a tile, so it no longer fits where it was
+        let (name, (offset, len)) = tiles.offsets.iter().map(|(name, &range)| (name.clone(), range)).min_by_key(|&(_, (offset, _))| offset).unwrap();
+        tiles.offsets.insert(name.clone(), (offset, len + 1));
+        tiles.reload_tilesets();
+        let _map = Map::load(&mut tiles, FINITE).unwrap();
+        assert_ne!(tiles.offsets[&name].0, offset);
+        let (count, offsets) = (tiles.tiles.len(), tiles.offsets.clone());
+        tiles.reload_tilesets();
+        let _map = Map::load(&mut tiles, FINITE).unwrap();
+        assert_eq!(tiles.tiles.len(), count);
+        assert_eq!(tiles.offsets, offsets);
+    }
+
+    #[test]
+    fn failed_checks_leave_tiles_and_scripts_alone() {
+        let lua = Lua::new();
+        let (mut tiles, _map) = load_finite(&lua);
+        tiles.reload_tilesets();
+        let stale = tiles.stale.clone();
+        lua.globals().set("camera", "old").unwrap();
+        let result = Map::load_checked(&mut tiles, FINITE, |_| Err("No textures".to_string()));
+        assert_eq!(result.err(), Some("No textures".to_string()));
+        assert_eq!(tiles.stale, stale);
+        assert_eq!(lua.globals().get::<_, String>("camera").unwrap(), "old");
+    }
+
+    #[test]
+    fn objects_from_queries_are_read_only() {
+        let lua = Lua::new();
+        let (_tiles, map) = load_finite(&lua);
+        lua.globals().set("world", map.world()).unwrap();
+        let found: usize = lua.exec("return #world:objects_in_rect(0, 0, world.width, world.height)", None).unwrap();
+        assert_eq!(found, map.objects.len());
+        assert!(lua.exec::<()>("world:objects_in_rect(0, 0, world.width, world.height)[1]:move(1, 0)", None).is_err());
+    }
+
+    ///The corners of a shape's bounding box to the nearest pixel, which hides the small margin ncollide adds
+    fn bounds(shape: &ShapeHandle2<f64>) -> (f64, f64, f64, f64) {
+        let aabb = shape.aabb(&Isometry2::identity());
+        (aabb.mins().x.round(), aabb.mins().y.round(), aabb.maxs().x.round(), aabb.maxs().y.round())
+    }
+
+    #[test]
+    fn triggers_turn_about_their_corner() {
+        let lua = Lua::new();
+        let (_tiles, mut map) = load_finite(&lua);
+        let tmx = r#"<?xml version="1.0" encoding="UTF-8"?>
+<map version="1.0" orientation="orthogonal" width="1" height="1" tilewidth="1" tileheight="1">
+ <objectgroup name="Triggers">
+  <object id="1" x="100" y="50" width="40" height="10" rotation="90"/>
+ </objectgroup>
+</map>"#;
+        let object = tiled::parse(tmx.as_bytes()).unwrap().object_groups.remove(0).objects.remove(0);
+        map.add_trigger(&lua, &object);
+        let handle = *map.triggers.keys().next_back().unwrap();
+        let trigger = map.world.collision_object(handle).unwrap();
+        let aabb = trigger.shape().aabb(trigger.position());
+        assert_eq!((aabb.mins().x.round(), aabb.mins().y.round(), aabb.maxs().x.round(), aabb.maxs().y.round()), (90.0, 50.0, 100.0, 90.0));
+    }
+
+    #[test]
+    fn hitboxes_turn_and_flip_with_their_tile() {
+        let shapes = [HitShape::Rect(10.0, 5.0, 20.0, 10.0)];
+        let hitbox = |rotation, flipped| bounds(&HitShape::compound(&shapes, 100.0, 50.0, rotation, flipped));
+        assert_eq!(hitbox(0, false), (10.0, 5.0, 30.0, 15.0));
+        assert_eq!(hitbox(1, false), (35.0, 10.0, 45.0, 30.0));
+        assert_eq!(hitbox(2, false), (70.0, 35.0, 90.0, 45.0));
+        assert_eq!(hitbox(3, false), (5.0, 70.0, 15.0, 90.0));
+        assert_eq!(hitbox(0, true), (70.0, 5.0, 90.0, 15.0));
+        assert_eq!(hitbox(1, true), (35.0, 70.0, 45.0, 90.0));
+    }
+
+    #[test]
+    fn concave_hitboxes_split_into_convex_parts_covering_the_same_area() {
+        fn area(points: &[Point2<f64>]) -> f64 {
+            (0..points.len()).map(|i| {
+                let (a, b) = (points[i], points[(i + 1) % points.len()]);
+                a.x * b.y - b.x * a.y
+            }).sum::<f64>() / 2.0
+        }
+        let l_shape: Vec<Point2<f64>> = [(0.0, 0.0), (20.0, 0.0), (20.0, 10.0), (10.0, 10.0), (10.0, 20.0), (0.0, 20.0)].iter()
+            .map(|&(x, y)| Point2::new(x, y))
+            .collect();
+        let parts = HitShape::convex_parts(l_shape);
+        assert!(parts.len() > 1);
+        assert!((parts.iter().map(|part| area(part)).sum::<f64>() - 300.0).abs() < 1e-9);
+        let shapes = [HitShape::Polygon(vec![(0.0, 0.0), (20.0, 0.0), (20.0, 10.0), (10.0, 10.0), (10.0, 20.0), (0.0, 20.0)])];
+        assert_eq!(bounds(&HitShape::compound(&shapes, 20.0, 20.0, 1, false)), (0.0, 0.0, 20.0, 20.0));
+    }
+
+    #[test]
+    fn fit_scaling_letterboxes_to_keep_the_view_shape() {
+        assert_eq!(Scaling::Fit.place((320.0, 240.0), (800.0, 480.0)), (80.0, 0.0, 720.0, 480.0));
+        assert_eq!(Scaling::Fit.place((320.0, 240.0), (640.0, 960.0)), (0.0, 240.0, 640.0, 720.0));
+    }
+
+    #[test]
+    fn integer_scaling_uses_whole_multiples() {
+        assert_eq!(Scaling::Integer.place((320.0, 240.0), (800.0, 600.0)), (80.0, 60.0, 720.0, 540.0));
+        //Never shrinks, so a view bigger than the window is cropped evenly
+        assert_eq!(Scaling::Integer.place((320.0, 240.0), (300.0, 200.0)), (-10.0, -20.0, 310.0, 220.0));
+    }
+
+    #[test]
+    fn stretch_scaling_fills_the_window() {
+        assert_eq!(Scaling::Stretch.place((320.0, 240.0), (1000.0, 300.0)), (0.0, 0.0, 1000.0, 300.0));
+    }
+
+    #[test]
+    fn shelves_fill_rows_then_start_new_ones_below() {
+        let mut shelves = Shelves::new(0, 100);
+        assert_eq!(shelves.place(60, 30), Some((0, 0)));
+        assert_eq!(shelves.place(40, 20), Some((60, 0)));
+        assert_eq!(shelves.place(10, 10), Some((0, 30)));
+        assert_eq!(shelves.place(50, 25), Some((10, 30)));
+        assert_eq!(shelves.height(), 55);
+    }
+
+    #[test]
+    fn shelves_refuse_images_once_full() {
+        let mut shelves = Shelves::new(0, 100);
+        assert_eq!(shelves.place(100, ATLAS_SIZE - 10), Some((0, 0)));
+        assert_eq!(shelves.place(10, 20), None);
+    }
+
+    #[test]
+    fn shelves_take_an_oversized_image_when_empty() {
+        let mut shelves = Shelves::new(0, 100);
+        assert_eq!(shelves.place(200, ATLAS_SIZE * 2), Some((0, 0)));
+        assert_eq!(shelves.place(1, 1), None);
+    }
+
+    #[test]
+    fn palette_cycles_need_a_range_inside_the_palette() {
+        assert_eq!(PaletteCycle::parse("palette.png", 8, "1 3 2; 4 7 -1").unwrap().len(), 2);
+        assert!(PaletteCycle::parse("palette.png", 8, "3 3 2").is_err());
+        assert!(PaletteCycle::parse("palette.png", 8, "5 2 2").is_err());
+        assert!(PaletteCycle::parse("palette.png", 8, "4 8 2").is_err());
+        assert!(PaletteCycle::parse("palette.png", 8, "4 6").is_err());
+    }
+
+    #[test]
+    fn palette_cycle_offsets_wrap_around_the_range() {
+        let cycle = PaletteCycle { palette: String::new(), start: 2, end: 5, speed: 2.0 };
+        assert_eq!(cycle.offset(0.0), 0);
+        assert_eq!(cycle.offset(0.5), 1);
+        assert_eq!(cycle.offset(2.0), 0);
+        assert_eq!(cycle.offset(2.6), 1);
+        let backwards = PaletteCycle { speed: -2.0, ..cycle };
+        assert_eq!(backwards.offset(0.5), 3);
+        assert_eq!(backwards.offset(2.0), 0);
+    }
+
+    #[test]
+    fn palette_cycles_over_the_same_range_replace_each_other() {
+        let mut cycles = Vec::new();
+        for &speed in &[1.0, 3.0] {
+            PaletteCycle { palette: "palette.png".to_string(), start: 1, end: 4, speed }.insert_into(&mut cycles);
+        }
+        PaletteCycle { palette: "other.png".to_string(), start: 1, end: 4, speed: 1.0 }.insert_into(&mut cycles);
+        assert_eq!(cycles.len(), 2);
+        assert_eq!(cycles[0], PaletteCycle { palette: "palette.png".to_string(), start: 1, end: 4, speed: 3.0 });
+        PaletteCycle { palette: "palette.png".to_string(), start: 1, end: 4, speed: 0.0 }.insert_into(&mut cycles);
+        assert_eq!(cycles.len(), 1);
+    }
+
+    #[test]
+    fn cycle_palette_rejects_bad_ranges() {
+        let lua = Lua::new();
+        let (_tiles, _map) = load_finite(&lua);
+        assert!(lua.exec::<()>("cycle_palette('walktest12-0', 1, 3, 2)", None).is_ok());
+        assert!(lua.exec::<()>("cycle_palette('walktest12-0', 3, 1, 2)", None).is_err());
+        assert!(lua.exec::<()>("cycle_palette('walktest12-0', 1, 1000, 2)", None).is_err());
+        assert!(lua.exec::<()>("cycle_palette('no such tile', 1, 3, 2)", None).is_err());
+    }
+
+    #[test]
+    fn destroying_a_stale_copy_leaves_the_object_reusing_its_handle() {
+        let lua = Lua::new();
+        let (tiles, mut map) = load_finite(&lua);
+        let spawn = "world:spawn('walktest12-0', 500, 500)";
+        lua.exec::<()>(spawn, None).unwrap();
+        map.apply_script_changes(&lua, &tiles).unwrap();
+        let (handle, object) = {
+            let (&handle, &(_, ref object)) = map.objects.iter().next_back().unwrap();
+            (handle, object.clone())
+        };
+        lua.globals().set("old", object).unwrap();
+        lua.exec::<()>("old:destroy()", None).unwrap();
+        map.apply_script_changes(&lua, &tiles).unwrap();
+        assert!(!map.objects.contains_key(&handle));
+        lua.exec::<()>(spawn, None).unwrap();
+        map.apply_script_changes(&lua, &tiles).unwrap();
+        assert!(map.objects.contains_key(&handle));
+        lua.exec::<()>("old:destroy()", None).unwrap();
+        map.apply_script_changes(&lua, &tiles).unwrap();
+        assert!(map.objects.contains_key(&handle));
+    }
+}
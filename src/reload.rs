@@ -0,0 +1,91 @@
+//! Polls the files a running map was loaded from so they can be reloaded in place when they change.
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::Path;
+use std::time::{Instant, SystemTime};
+use super::{Map, Tiles, Graphics};
+
+///How often to check for changes, in seconds
+const POLL_INTERVAL: f64 = 0.5;
+
+pub struct Watcher {
+    files: HashMap<String, Option<SystemTime>>,
+    last_poll: Instant,
+}
+
+impl Watcher {
+    pub fn new() -> Watcher {
+        Watcher {
+            files: HashMap::new(),
+            last_poll: Instant::now(),
+        }
+    }
+
+    pub fn watch(&mut self, path: &str) {
+        if !self.files.contains_key(path) {
+            self.files.insert(path.to_string(), modified(path));
+        }
+    }
+
+    ///Watches the map file, its external tilesets, its triggers' scripts, and every script, palette and image loaded so far
+    pub fn watch_resources<R: gfx::Resources, F: gfx::Factory<R>>(&mut self, filename: &str, map: &Map, tiles: &Tiles, graphics: &Graphics<R, F>) {
+        self.watch(filename);
+        for tileset in tilesets(filename) {
+            self.watch(&tileset);
+        }
+        for trigger in map.triggers.values() {
+            if let Some(ref path) = trigger.path {
+                self.watch(path);
+            }
+        }
+        for tile in &tiles.tiles {
+            if let Some(ref script) = tile.script {
+                self.watch(&script.path);
+            }
+        }
+        for path in graphics.watched_paths() {
+            self.watch(&path);
+        }
+    }
+
+    ///The watched files that changed since the last poll, or nothing if it's too soon to check again
+    pub fn poll(&mut self) -> Vec<String> {
+        let elapsed = self.last_poll.elapsed();
+        if elapsed.as_secs() as f64 + (elapsed.subsec_nanos() as f64 / 1.0e9) < POLL_INTERVAL {
+            return Vec::new()
+        }
+        self.last_poll = Instant::now();
+        let mut changed = Vec::new();
+        for (path, last_modified) in &mut self.files {
+            let modified = modified(path);
+            if modified != *last_modified {
+                *last_modified = modified;
+                changed.push(path.clone());
+            }
+        }
+        changed
+    }
+}
+
+fn modified(path: &str) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+///Paths of the external tilesets a map uses, since the parsed map doesn't keep them
+fn tilesets(map: &str) -> Vec<String> {
+    let mut contents = String::new();
+    if File::open(map).and_then(|mut file| file.read_to_string(&mut contents)).is_err() {
+        return Vec::new()
+    }
+    let directory = Path::new(map).parent().unwrap_or(Path::new(""));
+    contents.split("<tileset").skip(1)
+        .filter_map(|tag| {
+            let tag = &tag[..tag.find('>').unwrap_or(tag.len())];
+            let start = tag.find("source=\"")? + "source=\"".len();
+            let end = start + tag[start..].find('"')?;
+            Some(directory.join(&tag[start..end]).to_string_lossy().into_owned())
+        })
+        .collect()
+}
@@ -0,0 +1,115 @@
+//! Draws a map offscreen and writes the frame out as a PNG, for screenshots without a window and golden-image tests.
+//!
+//! `lgj2018 --render output.png [map] [ticks] [inputs] [--compare reference.png]`
+//!
+//! The map runs for `ticks` ticks (0 by default) with the same inputs file as `--headless`, then one frame is drawn
+//! through a headless GL context, which can be a software one like OSMesa or llvmpipe. With `--compare` the frame
+//! is checked against a reference image, exiting with 1 if any pixel differs.
+//!
+//! `cargo test -- --ignored` checks Finite.tmx against assets/golden/Finite-0.png the same way. It's ignored by
+//! default since it needs a GL context. After a change meant to alter the picture, write a new reference with
+//! `--render assets/golden/Finite-0.png` and check it in.
+
+use gfx;
+use gfx_window_glutin;
+use glutin::{self, GlContext};
+use image;
+use rlua::{Lua, AnyUserData};
+use super::{Controls, Map, Tiles, Renderer, ColorFormat, DepthFormat, set_globals, camera::Camera};
+use headless::load_inputs;
+
+pub fn run(args: &[String]) {
+    let mut args = args.to_vec();
+    let compare = args.iter().position(|arg| arg == "--compare").map(|index| {
+        let path = args.get(index + 1).expect("--compare needs a reference image").clone();
+        args.drain(index..index + 2);
+        path
+    });
+    let output = args.get(0).expect("--render needs an output path");
+    let filename = args.get(1).map_or("assets/tiled/Finite.tmx", |arg| arg.as_str());
+    let ticks: u64 = args.get(2).map_or(0, |arg| arg.parse().expect("Tick count should be a number"));
+    let inputs = args.get(3).map_or(Vec::new(), |path| load_inputs(path));
+
+    let frame = render(filename, ticks, inputs);
+    frame.save(output).expect("Unable to write the rendered frame");
+    println!("Wrote {} after {} ticks", output, ticks);
+
+    if let Some(reference) = compare {
+        if let Err(error) = compare_to(&frame, &reference) {
+            println!("{}", error);
+            std::process::exit(1);
+        }
+        println!("Matches the reference");
+    }
+}
+
+///Runs the map for `ticks` ticks, then draws one frame of it
+fn render(filename: &str, ticks: u64, inputs: Vec<(u64, Controls)>) -> image::RgbaImage {
+    let lua = Lua::new();
+    let mut tiles = Tiles::new(&lua);
+    let mut map = Map::load(&mut tiles, filename).unwrap_or_else(|error| panic!("{}", error));
+    let tick = 1.0 / map.tick_rate;
+    set_globals(&lua, tick);
+    let globals = lua.globals();
+
+    let mut inputs = inputs.into_iter().peekable();
+    let mut controls = Controls::default();
+    let mut counter = 0.0;
+    for i in 0..ticks {
+        while inputs.peek().map_or(false, |&(start, _)| start <= i) {
+            controls = inputs.next().unwrap().1;
+        }
+        globals.set("controls", controls).unwrap();
+        counter += tick;
+        map.tick(&lua, &tiles, counter);
+    }
+
+    let (width, height) = {
+        let camera: AnyUserData = globals.get("camera").expect("Unable to get camera");
+        let camera = camera.borrow::<Camera>().expect("camera should be a camera");
+        (camera.width.round() as u16, camera.height.round() as u16)
+    };
+    //Nothing is drawn to the context's own framebuffer, but it still needs one
+    let context = glutin::HeadlessRendererBuilder::new(width as u32, height as u32)
+        .build()
+        .expect("Unable to create a headless GL context");
+    unsafe { context.make_current().expect("Unable to make the GL context current") };
+    let (mut device, mut factory, _, _) = gfx_window_glutin::init_headless::<ColorFormat, DepthFormat>(
+        &context,
+        (width, height, 1, gfx::texture::AaMode::Single)
+    );
+    let mut encoder: gfx::Encoder<_, _> = factory.create_command_buffer().into();
+    let mut renderer = Renderer::new(factory, &tiles);
+    renderer.draw(&mut encoder, &lua, &map, &tiles, counter, 1.0);
+    renderer.capture(&mut encoder, &mut device)
+}
+
+///Checks a frame against the reference image at `path`, saying how it differs if it does
+fn compare_to(frame: &image::RgbaImage, path: &str) -> Result<(), String> {
+    let reference = image::open(path).map_err(|error| format!("Unable to open {}: {}", path, error))?.to_rgba();
+    if reference.dimensions() != frame.dimensions() {
+        return Err(format!("Frame is {:?} but the reference is {:?}", frame.dimensions(), reference.dimensions()))
+    }
+    let different = frame.pixels().zip(reference.pixels()).filter(|&(a, b)| a != b).count();
+    if different > 0 {
+        return Err(format!("{} pixels differ from the reference", different))
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tests::FINITE;
+
+    const GOLDEN: &str = "assets/golden/Finite-0.png";
+
+    #[test]
+    #[ignore]
+    fn finite_matches_golden_image() {
+        let frame = render(FINITE, 0, Vec::new());
+        if let Err(error) = compare_to(&frame, GOLDEN) {
+            panic!("{}", error);
+        }
+    }
+}
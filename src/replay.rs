@@ -0,0 +1,140 @@
+//! Recording and replaying the controls for every tick, so a run can be reproduced exactly.
+//!
+//! A replay file starts with `delta <bits>`, the tick length as the hex bits of an `f64`, followed by one
+//! line per tick with the controls as a hex bitmask and the state hash after that tick.
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use super::Controls;
+
+pub struct Recorder {
+    file: BufWriter<File>,
+}
+
+impl Recorder {
+    pub fn create(path: &str, delta: f64) -> Recorder {
+        let mut file = BufWriter::new(File::create(path).expect("Couldn't create recording"));
+        writeln!(file, "delta {:x}", delta.to_bits()).expect("Failed to write recording");
+        Recorder {
+            file,
+        }
+    }
+
+    pub fn record(&mut self, controls: Controls, hash: u64) {
+        writeln!(self.file, "{:x} {:x}", controls.bits(), hash).expect("Failed to write recording");
+    }
+}
+
+pub struct Replay {
+    pub delta: f64,
+    ticks: Vec<(Controls, Option<u64>)>,
+    position: usize,
+}
+
+impl Replay {
+    pub fn load(path: &str) -> Replay {
+        let file = File::open(path).expect("Couldn't open replay");
+        let mut lines = BufReader::new(file).lines().map(|line| line.expect("Failed to read replay"));
+        let header = lines.next().expect("Replay is empty");
+        let delta = header.trim().trim_left_matches("delta").trim();
+        let delta = f64::from_bits(u64::from_str_radix(delta, 16).expect("Replay has no delta"));
+        let mut ticks = Vec::new();
+        for line in lines {
+            let mut words = line.split_whitespace();
+            let controls = match words.next() {
+                Some(bits) => Controls::from_bits(u8::from_str_radix(bits, 16).expect("Invalid controls in replay")),
+                None => continue
+            };
+            let hash = words.next().map(|hash| u64::from_str_radix(hash, 16).expect("Invalid hash in replay"));
+            ticks.push((controls, hash));
+        }
+        Replay {
+            delta,
+            ticks,
+            position: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.ticks.len()
+    }
+
+    ///The controls for the next tick, or None once the replay is over
+    pub fn next(&mut self) -> Option<Controls> {
+        self.ticks.get(self.position).map(|&(controls, _)| controls)
+    }
+
+    ///Checks the state after the tick from `next` against the recording and moves on to the next tick
+    pub fn check(&mut self, hash: u64) -> bool {
+        let expected = self.ticks.get(self.position).and_then(|&(_, hash)| hash);
+        self.position += 1;
+        match expected {
+            Some(expected) if expected != hash => {
+                println!("Replay diverged on tick {}: expected state {:x}, got {:x}", self.position - 1, expected, hash);
+                false
+            }
+            _ => true
+        }
+    }
+}
+
+///Takes `--record <path>` and `--replay <path>` out of the arguments, returning the rest
+pub fn parse_args(args: &[String]) -> (Vec<String>, Option<String>, Option<Replay>) {
+    let mut rest = Vec::new();
+    let mut record = None;
+    let mut replay = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--record" => record = Some(args.next().expect("--record needs a path").clone()),
+            "--replay" => replay = Some(Replay::load(args.next().expect("--replay needs a path"))),
+            _ => rest.push(arg.clone())
+        }
+    }
+    (rest, record, replay)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recordings_load_back_as_they_were_written() {
+        let path = ::std::env::temp_dir().join("lgj2018-replay-round-trip.txt");
+        let path = path.to_str().unwrap();
+        let delta = 1.0 / 120.0;
+        let ticks = [
+            (Controls::default(), 0),
+            (Controls { left: true, a: true, ..Controls::default() }, 0x0123_4567_89ab_cdef),
+            (Controls { up: true, down: true, right: true, b: true, ..Controls::default() }, u64::max_value()),
+        ];
+        {
+            let mut recorder = Recorder::create(path, delta);
+            for &(controls, hash) in &ticks {
+                recorder.record(controls, hash);
+            }
+        }
+        let mut replay = Replay::load(path);
+        assert_eq!(replay.delta.to_bits(), delta.to_bits());
+        assert_eq!(replay.len(), ticks.len());
+        for &(controls, hash) in &ticks {
+            assert_eq!(replay.next().map(|replayed| replayed.bits()), Some(controls.bits()));
+            assert!(replay.check(hash));
+        }
+        assert!(replay.next().is_none());
+    }
+
+    #[test]
+    fn replays_notice_a_different_state() {
+        let path = ::std::env::temp_dir().join("lgj2018-replay-divergence.txt");
+        let path = path.to_str().unwrap();
+        {
+            let mut recorder = Recorder::create(path, 0.01);
+            recorder.record(Controls::default(), 1);
+            recorder.record(Controls::default(), 2);
+        }
+        let mut replay = Replay::load(path);
+        assert!(replay.check(1));
+        assert!(!replay.check(3));
+    }
+}
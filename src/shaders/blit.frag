@@ -0,0 +1,10 @@
+#version 130
+
+uniform sampler2D screen;
+
+in vec2 v_uv;
+out vec4 Target0;
+
+void main() {
+	Target0 = texture(screen, v_uv);
+}
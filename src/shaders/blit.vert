@@ -0,0 +1,10 @@
+#version 130
+
+in vec2 pos;
+in vec2 uv;
+out vec2 v_uv;
+
+void main() {
+    v_uv = uv;
+	gl_Position = vec4(pos, 0.0, 1.0);
+}
@@ -0,0 +1,19 @@
+#version 130
+
+//Same view as shader.vert
+uniform vec2 camera;
+uniform float zoom;
+uniform float rotation;
+uniform float width;
+uniform float height;
+
+in vec2 pos;
+in vec4 color;
+out vec4 v_color;
+
+void main() {
+    v_color = color;
+    vec2 offset = (pos - camera) * zoom;
+    offset = vec2(offset.x * cos(rotation) + offset.y * sin(rotation), offset.y * cos(rotation) - offset.x * sin(rotation));
+	gl_Position = vec4(offset.x / width * 2.0, offset.y / height * -2.0, 0.0, 1.0);
+}
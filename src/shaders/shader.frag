@@ -1,16 +1,15 @@
-#version 120
-#extension GL_EXT_gpu_shader4: enable
+#version 130
 
-uniform sampler1D palette;
+uniform sampler2D palette;
 uniform usampler2D sprite;
 
-varying in vec2 v_uv;
-varying out vec4 Target0;
+in vec2 v_uv;
+flat in int v_palette_row;
+out vec4 Target0;
 
 void main() {
-	Target0 = texture1D(palette, float(texture2D(sprite, v_uv).r) / 64.0);
-	if(Target0.a > 0.5) {
-	    Target0 = texture1D(palette, float(texture2D(sprite, v_uv).r % uint(64)) / 64.0);
-	}
+	//Rows past the end of the palette image use its last row
+	int row = min(v_palette_row, textureSize(palette, 0).y - 1);
+	Target0 = texelFetch(palette, ivec2(int(texture(sprite, v_uv).r), row), 0);
 	//Target0 = vec4(1.0, 1.0, 1.0, 1.0);
 }
@@ -1,16 +1,23 @@
 #version 130
 
-uniform int x;
-uniform int y;
+//Middle of the view in world pixels
+uniform vec2 camera;
+uniform float zoom;
+uniform float rotation;
+//Size of the view in world pixels at a zoom of 1
 uniform float width;
 uniform float height;
-uniform float flip;
 
 in vec2 pos;
 in vec2 uv;
-varying out vec2 v_uv;
+in float palette_row;
+out vec2 v_uv;
+flat out int v_palette_row;
 
 void main() {
-    v_uv = uv * vec2(flip, 1.0);
-	gl_Position = vec4(float(pos.x + x) / width * 2.0 - 1.0, float(pos.y + y) / height * -2.0 + 1.0, 0.0, 1.0);
+    v_uv = uv;
+    v_palette_row = int(palette_row);
+    vec2 offset = (pos - camera) * zoom;
+    offset = vec2(offset.x * cos(rotation) + offset.y * sin(rotation), offset.y * cos(rotation) - offset.x * sin(rotation));
+	gl_Position = vec4(offset.x / width * 2.0, offset.y / height * -2.0, 0.0, 1.0);
 }
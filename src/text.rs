@@ -0,0 +1,239 @@
+//! Bitmap fonts, and the text scripts draw with `draw_text(x, y, str, palette, options)`.
+//!
+//! A font is a spritesheet tileset with a glyph in each tile, in character order starting from the tileset's
+//! `first_character` property (32, a space, by default). The map picks its font by naming the tileset in a `font`
+//! property. Glyphs go through the palette like any other tile, so `palette` picks the row to colour them with.
+//!
+//! Text is positioned in view pixels from the top left, so it stays put as the camera moves. `options` can have
+//! a `width` to wrap lines at, breaking at spaces, and an `align` of "left", "center" or "right" around `x`.
+//! Whatever scripts draw during a tick is shown until the end of the next one.
+
+use rlua::{self, Lua, Table};
+use tiled::{Tileset, PropertyValue};
+
+pub struct Font {
+    ///Tile index of the first glyph
+    first_tile: usize,
+    glyphs: usize,
+    first_character: u32,
+    ///Size of every glyph, so also how far apart characters and lines are
+    pub width: f64,
+    pub height: f64,
+}
+
+impl Font {
+    ///A font from `tileset`, which `Tiles::load` put at `first_tile` onwards
+    pub fn new(tileset: &Tileset, first_tile: usize, glyphs: usize) -> Font {
+        let first_character = match tileset.properties.get("first_character") {
+            Some(&PropertyValue::IntValue(v)) => v as u32,
+            Some(&PropertyValue::StringValue(ref v)) => v.chars().next().map_or(32, |character| character as u32),
+            _ => 32
+        };
+        Font {
+            first_tile,
+            glyphs,
+            first_character,
+            width: tileset.tile_width as f64,
+            height: tileset.tile_height as f64,
+        }
+    }
+
+    ///The tile to draw for a character, or None if the font doesn't have it
+    fn glyph(&self, character: char) -> Option<usize> {
+        let index = (character as u32).checked_sub(self.first_character)? as usize;
+        if index < self.glyphs {
+            Some(self.first_tile + index)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Align {
+    Left,
+    Center,
+    Right,
+}
+
+pub struct Text {
+    x: f64,
+    y: f64,
+    text: String,
+    ///Row of the palette to colour the glyphs with
+    pub palette: usize,
+    ///Width to wrap lines at, in pixels
+    wrap: Option<f64>,
+    align: Align,
+}
+
+impl Text {
+    ///Text wrapped across the top of a view `width` pixels wide, for messages from the game rather than scripts
+    pub fn overlay(text: &str, width: f64) -> Text {
+        Text {
+            x: 0.0,
+            y: 0.0,
+            text: text.to_string(),
+            palette: 0,
+            wrap: Some(width),
+            align: Align::Left,
+        }
+    }
+
+    ///The tile and top left corner of every character to draw
+    pub fn layout(&self, font: &Font) -> Vec<(usize, f64, f64)> {
+        let mut glyphs = Vec::new();
+        for (row, line) in self.lines(font).iter().enumerate() {
+            let width = line.chars().count() as f64 * font.width;
+            let left = match self.align {
+                Align::Left => self.x,
+                Align::Center => (self.x - width / 2.0).round(),
+                Align::Right => self.x - width,
+            };
+            let top = self.y + row as f64 * font.height;
+            for (column, character) in line.chars().enumerate() {
+                if let Some(tile) = font.glyph(character) {
+                    glyphs.push((tile, left + column as f64 * font.width, top));
+                }
+            }
+        }
+        glyphs
+    }
+
+    ///Splits the text at newlines, then at spaces wherever a line would be wider than `wrap`
+    fn lines(&self, font: &Font) -> Vec<String> {
+        let columns = self.wrap.map(|wrap| ((wrap / font.width).floor() as usize).max(1));
+        let mut lines = Vec::new();
+        for paragraph in self.text.split('\n') {
+            let columns = match columns {
+                Some(columns) => columns,
+                None => {
+                    lines.push(paragraph.to_string());
+                    continue
+                }
+            };
+            let mut line: Vec<char> = Vec::new();
+            for word in paragraph.split(' ') {
+                let mut word: Vec<char> = word.chars().collect();
+                if !line.is_empty() && line.len() + 1 + word.len() > columns {
+                    lines.push(line.drain(..).collect());
+                }
+                //Words too long for any line get broken wherever they reach the edge
+                while word.len() > columns {
+                    if !line.is_empty() {
+                        lines.push(line.drain(..).collect());
+                    }
+                    lines.push(word.drain(..columns).collect());
+                }
+                if !line.is_empty() {
+                    line.push(' ');
+                }
+                line.extend(word);
+            }
+            lines.push(line.into_iter().collect());
+        }
+        lines
+    }
+}
+
+///Adds the `draw_text` global, which queues text until `take` collects it
+pub fn set_globals(lua: &Lua) -> rlua::Result<()> {
+    lua.set_named_registry_value("text", lua.create_table()?)?;
+    let draw_text = lua.create_function(|lua, (x, y, text, palette, options): (f64, f64, String, Option<usize>, Option<Table>)| {
+        let queued: Table = lua.named_registry_value("text")?;
+        let entry = lua.create_table()?;
+        entry.set("x", x)?;
+        entry.set("y", y)?;
+        entry.set("text", text)?;
+        entry.set("palette", palette.unwrap_or(0))?;
+        if let Some(options) = options {
+            entry.set("width", options.get::<_, Option<f64>>("width")?)?;
+            if let Some(align) = options.get::<_, Option<String>>("align")? {
+                if align != "left" && align != "center" && align != "right" {
+                    return Err(rlua::Error::RuntimeError(format!("Unknown text alignment {}", align)))
+                }
+                entry.set("align", align)?;
+            }
+        }
+        queued.set(queued.len()? + 1, entry)
+    })?;
+    lua.globals().set("draw_text", draw_text)
+}
+
+///Everything drawn with `draw_text` since the last call
+pub fn take(lua: &Lua) -> rlua::Result<Vec<Text>> {
+    let queued: Table = lua.named_registry_value("text")?;
+    lua.set_named_registry_value("text", lua.create_table()?)?;
+    let mut texts = Vec::new();
+    for entry in queued.sequence_values::<Table>() {
+        let entry = entry?;
+        let align = match entry.get::<_, Option<String>>("align")?.as_ref().map(|align| align.as_str()) {
+            Some("center") => Align::Center,
+            Some("right") => Align::Right,
+            _ => Align::Left
+        };
+        texts.push(Text {
+            x: entry.get("x")?,
+            y: entry.get("y")?,
+            text: entry.get("text")?,
+            palette: entry.get("palette")?,
+            wrap: entry.get("width")?,
+            align,
+        });
+    }
+    Ok(texts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn font() -> Font {
+        Font {
+            first_tile: 0,
+            glyphs: 95,
+            first_character: 32,
+            width: 8.0,
+            height: 8.0,
+        }
+    }
+
+    fn text(text: &str, wrap: Option<f64>) -> Text {
+        Text {
+            x: 0.0,
+            y: 0.0,
+            text: text.to_string(),
+            palette: 0,
+            wrap,
+            align: Align::Left,
+        }
+    }
+
+    #[test]
+    fn lines_only_split_at_newlines_without_a_width() {
+        assert_eq!(text("one two\nthree", None).lines(&font()), vec!["one two", "three"]);
+    }
+
+    #[test]
+    fn lines_wrap_at_spaces() {
+        let lines = text("the quick brown fox", Some(80.0)).lines(&font());
+        assert_eq!(lines, vec!["the quick", "brown fox"]);
+        let lines = text("the quick\nbrown fox jumps", Some(80.0)).lines(&font());
+        assert_eq!(lines, vec!["the quick", "brown fox", "jumps"]);
+    }
+
+    #[test]
+    fn long_words_break_at_the_edge() {
+        let lines = text("a abcdefghij b", Some(32.0)).lines(&font());
+        assert_eq!(lines, vec!["a", "abcd", "efgh", "ij b"]);
+    }
+
+    #[test]
+    fn layout_aligns_each_line() {
+        let mut centred = text("ab\nabcd", None);
+        centred.x = 100.0;
+        centred.align = Align::Center;
+        let positions: Vec<(f64, f64)> = centred.layout(&font()).into_iter().map(|(_, x, y)| (x, y)).collect();
+        assert_eq!(positions, vec![(92.0, 0.0), (100.0, 0.0), (84.0, 8.0), (92.0, 8.0), (100.0, 8.0), (108.0, 8.0)]);
+    }
+}